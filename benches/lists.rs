@@ -0,0 +1,119 @@
+//! List operations on lists of a hundred thousand values and more. Pushing
+//! and popping at either end should not depend on the length of the list.
+//!
+//! Run with `cargo bench --bench lists`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use serde::Serialize;
+use tea_codec::keyvalue::*;
+use tea_keyvalue_provider::protocol::*;
+use tea_keyvalue_provider::KeyvalueProvider;
+use wascc_codec::capabilities::CapabilityProvider;
+use wascc_codec::serialize;
+
+const ACTOR: &str = "MBENCH";
+const LONG_LIST: u32 = 100_000;
+
+fn call<T: Serialize>(provider: &KeyvalueProvider, op: &str, req: T) -> Vec<u8> {
+    provider
+        .handle_call(ACTOR, op, &serialize(req).unwrap())
+        .unwrap()
+}
+
+fn push(provider: &KeyvalueProvider, op: &str, key: &str, value: u32) {
+    call(
+        provider,
+        op,
+        PushRequest {
+            key: key.to_string(),
+            values: vec![value.to_le_bytes().to_vec()],
+        },
+    );
+}
+
+fn pop(provider: &KeyvalueProvider, op: &str, key: &str) {
+    call(
+        provider,
+        op,
+        PopRequest {
+            key: key.to_string(),
+            count: 1,
+        },
+    );
+}
+
+fn populate() -> KeyvalueProvider {
+    let provider = KeyvalueProvider::new();
+    call(
+        &provider,
+        OP_RPUSH,
+        PushRequest {
+            key: "long".to_string(),
+            values: (0..LONG_LIST).map(|v| v.to_le_bytes().to_vec()).collect(),
+        },
+    );
+    provider
+}
+
+fn bench_lists(c: &mut Criterion) {
+    let provider = populate();
+
+    // Each push is undone by a pop so the list keeps its length
+    c.bench_function("lpush and lpop on a long list", |b| {
+        b.iter(|| {
+            push(&provider, OP_LPUSH, "long", 0);
+            pop(&provider, OP_LPOP, "long");
+        })
+    });
+    c.bench_function("rpush and rpop on a long list", |b| {
+        b.iter(|| {
+            push(&provider, OP_RPUSH, "long", 0);
+            pop(&provider, OP_RPOP, "long");
+        })
+    });
+    c.bench_function("lrange 100 from the middle of a long list", |b| {
+        b.iter(|| {
+            call(
+                &provider,
+                OP_RANGE,
+                ListRangeRequest {
+                    key: "long".to_string(),
+                    start: LONG_LIST as i32 / 2,
+                    stop: LONG_LIST as i32 / 2 + 99,
+                },
+            )
+        })
+    });
+    c.bench_function("lindex the middle of a long list", |b| {
+        b.iter(|| {
+            call(
+                &provider,
+                OP_LINDEX,
+                ListIndexRequest {
+                    key: "long".to_string(),
+                    index: LONG_LIST as i64 / 2,
+                },
+            )
+        })
+    });
+    // Grows a list from nothing one value at a time, the way event logs do
+    c.bench_function("lpush a long list one value at a time", |b| {
+        b.iter_batched(
+            KeyvalueProvider::new,
+            |provider| {
+                for value in 0..LONG_LIST {
+                    push(&provider, OP_LPUSH, "log", value);
+                }
+                provider
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_lists
+}
+criterion_main!(benches);
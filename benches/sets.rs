@@ -0,0 +1,115 @@
+//! Set operations on a store that also holds a million unrelated keys. Only
+//! the requested keys should matter, so these should not get slower as the
+//! rest of the store grows.
+//!
+//! Run with `cargo bench --bench sets`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use serde::Serialize;
+use tea_codec::keyvalue::*;
+use tea_keyvalue_provider::KeyvalueProvider;
+use wascc_codec::capabilities::CapabilityProvider;
+use wascc_codec::serialize;
+
+const ACTOR: &str = "MBENCH";
+const UNRELATED_KEYS: usize = 1_000_000;
+const LARGE_SET: u32 = 100_000;
+const SMALL_SET: u32 = 10;
+
+fn call<T: Serialize>(provider: &KeyvalueProvider, op: &str, req: T) -> Vec<u8> {
+    provider
+        .handle_call(ACTOR, op, &serialize(req).unwrap())
+        .unwrap()
+}
+
+fn add(provider: &KeyvalueProvider, key: &str, member: u32) {
+    call(
+        provider,
+        OP_SET_ADD,
+        SetAddRequest {
+            key: key.to_string(),
+            value: member.to_le_bytes().to_vec(),
+        },
+    );
+}
+
+fn populate() -> KeyvalueProvider {
+    let provider = KeyvalueProvider::new();
+    for i in 0..UNRELATED_KEYS {
+        call(
+            &provider,
+            OP_SET,
+            SetRequest {
+                key: format!("unrelated:{}", i),
+                value: vec![0; 8],
+            },
+        );
+    }
+    for member in 0..LARGE_SET {
+        add(&provider, "large", member);
+    }
+    for member in 0..SMALL_SET {
+        add(&provider, "small", member * 1000);
+    }
+    provider
+}
+
+fn keys(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(|k| k.to_string()).collect()
+}
+
+fn bench_sets(c: &mut Criterion) {
+    let provider = populate();
+
+    c.bench_function("sinter large and small", |b| {
+        b.iter(|| {
+            call(
+                &provider,
+                OP_SET_INTERSECT,
+                SetIntersectionRequest {
+                    keys: keys(&["large", "small"]),
+                },
+            )
+        })
+    });
+    c.bench_function("sinter with a missing key", |b| {
+        b.iter(|| {
+            call(
+                &provider,
+                OP_SET_INTERSECT,
+                SetIntersectionRequest {
+                    keys: keys(&["large", "missing"]),
+                },
+            )
+        })
+    });
+    c.bench_function("sunion small and small", |b| {
+        b.iter(|| {
+            call(
+                &provider,
+                OP_SET_UNION,
+                SetUnionRequest {
+                    keys: keys(&["small", "small"]),
+                },
+            )
+        })
+    });
+    c.bench_function("smembers small", |b| {
+        b.iter(|| {
+            call(
+                &provider,
+                OP_SET_QUERY,
+                SetQueryRequest {
+                    key: "small".to_string(),
+                },
+            )
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = bench_sets
+}
+criterion_main!(benches);
@@ -0,0 +1,184 @@
+//! Where `KeyValueStore` keeps its items. The store implements every
+//! scalar, atomic, list, set and sorted vec operation on top of this trait, so
+//! a backend only has to know how to find, replace and remove whole items.
+
+use crate::kv::{ItemType, KeyValueItem};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::mem;
+use std::ops::Bound;
+
+/// Configuration value choosing the backend, either `memory` (the default) or `disk`
+pub const STORAGE_KEY: &str = "storage";
+/// Data file of the `disk` backend
+pub const STORAGE_PATH_KEY: &str = "storage_path";
+pub const MEMORY_STORAGE: &str = "memory";
+pub const DISK_STORAGE: &str = "disk";
+
+pub trait Backend: Send + Sync {
+    /// The `storage` configuration value that selects this backend
+    fn name(&self) -> &'static str;
+
+    fn get(&self, key: &str) -> Result<Option<Cow<'_, KeyValueItem>>, Box<dyn Error>>;
+
+    /// The type of the item stored at `key`. This default gets the item, so
+    /// backends that have to read and decode items should override it.
+    fn item_type(&self, key: &str) -> Result<Option<ItemType>, Box<dyn Error>> {
+        Ok(self.get(key)?.map(|item| item.item_type()))
+    }
+
+    /// Hands the item stored at `key`, or `None` if there is none, to `f`. If
+    /// `f` returns true, whatever it left in the slot is stored back and leaving
+    /// `None` removes the key. If it returns false the slot was not changed.
+    fn update(
+        &mut self,
+        key: &str,
+        f: &mut dyn FnMut(&mut Option<KeyValueItem>) -> bool,
+    ) -> Result<(), Box<dyn Error>>;
+
+    fn put(&mut self, key: &str, item: KeyValueItem) -> Result<(), Box<dyn Error>> {
+        let mut item = Some(item);
+        self.update(key, &mut |slot| {
+            *slot = item.take();
+            true
+        })
+    }
+
+    /// Removes the key together with its expiry
+    fn remove(&mut self, key: &str) -> Result<(), Box<dyn Error>>;
+
+    fn keys(&self) -> Vec<String>;
+
+    /// Up to `count` of the keys starting with `prefix`, in byte order, from
+    /// the first one after `after`, or from the first one at all if it is
+    /// `None`. This default sorts every key on each call, so backends that
+    /// keep their keys in order should override it.
+    fn keys_after(&self, prefix: &str, after: Option<&str>, count: usize) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .keys()
+            .into_iter()
+            .filter(|k| k.starts_with(prefix) && after.map_or(true, |after| k.as_str() > after))
+            .collect();
+        keys.sort();
+        keys.truncate(count);
+        keys
+    }
+
+    fn expiry(&self, key: &str) -> Option<u64>;
+
+    fn set_expiry(&mut self, key: &str, at: Option<u64>) -> Result<(), Box<dyn Error>>;
+
+    /// Keys whose deadline is at or before `now`
+    fn expired(&self, now: u64) -> Vec<String>;
+
+    /// Housekeeping such as compaction, run periodically under the store's write lock
+    fn maintain(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// `Backend::keys_after` for backends that index their keys with a `BTreeMap`
+pub fn ordered_keys_after<V>(
+    map: &BTreeMap<String, V>,
+    prefix: &str,
+    after: Option<&str>,
+    count: usize,
+) -> Vec<String> {
+    let start = match after {
+        Some(after) => Bound::Excluded(after),
+        None => Bound::Included(prefix),
+    };
+    map.range::<str, _>((start, Bound::Unbounded))
+        .map(|(k, _)| k)
+        .skip_while(|k| k.as_str() < prefix)
+        .take_while(|k| k.starts_with(prefix))
+        .take(count)
+        .cloned()
+        .collect()
+}
+
+#[derive(Default)]
+pub struct MemoryBackend {
+    items: BTreeMap<String, KeyValueItem>,
+    /// Expiry deadlines in milliseconds since the unix epoch. Keys without a
+    /// deadline live until they are deleted.
+    expirations: HashMap<String, u64>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        MEMORY_STORAGE
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Cow<'_, KeyValueItem>>, Box<dyn Error>> {
+        Ok(self.items.get(key).map(Cow::Borrowed))
+    }
+
+    fn update(
+        &mut self,
+        key: &str,
+        f: &mut dyn FnMut(&mut Option<KeyValueItem>) -> bool,
+    ) -> Result<(), Box<dyn Error>> {
+        match self.items.get_mut(key) {
+            Some(item) => {
+                // Work on the item in place rather than re-inserting it under a new key
+                let mut slot = Some(mem::replace(item, KeyValueItem::Atomic(0)));
+                f(&mut slot);
+                match slot {
+                    Some(updated) => *item = updated,
+                    None => self.remove(key)?,
+                }
+            }
+            None => {
+                let mut slot = None;
+                if f(&mut slot) {
+                    if let Some(item) = slot {
+                        self.items.insert(key.to_string(), item);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.items.remove(key);
+        self.expirations.remove(key);
+        Ok(())
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.items.keys().cloned().collect()
+    }
+
+    fn keys_after(&self, prefix: &str, after: Option<&str>, count: usize) -> Vec<String> {
+        ordered_keys_after(&self.items, prefix, after, count)
+    }
+
+    fn expiry(&self, key: &str) -> Option<u64> {
+        self.expirations.get(key).cloned()
+    }
+
+    fn set_expiry(&mut self, key: &str, at: Option<u64>) -> Result<(), Box<dyn Error>> {
+        match at {
+            Some(at) => self.expirations.insert(key.to_string(), at),
+            None => self.expirations.remove(key),
+        };
+        Ok(())
+    }
+
+    fn expired(&self, now: u64) -> Vec<String> {
+        self.expirations
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,508 @@
+//! Storage backend that keeps values on disk and only an index in memory.
+//!
+//! The data file is a log of records framed as described in `frame`. Every
+//! write appends the new value of the key, a deletion or an expiry change, and
+//! the index points at the latest value of each key so reads can seek straight
+//! to it. Space taken by values that have since been overwritten is reclaimed
+//! by `maintain`, which rewrites the file with only the live values once they
+//! are outweighed by garbage.
+//!
+//! A record always holds a whole item, so every change to a list, set, sorted
+//! vec, sorted set or hash reads, decodes and appends all of it. A write is on
+//! disk before it returns, collections included, but it costs as much as the
+//! collection is large.
+//!
+//! Appends are flushed to the operating system right away but only synced to
+//! disk from `maintain`, so a machine crash can lose about a second of writes.
+
+use crate::backend::{ordered_keys_after, Backend, DISK_STORAGE};
+use crate::frame::{self, frame, replace, temp_path, FRAME_LEN, HEADER_LEN};
+use crate::kv::{ItemType, KeyValueItem};
+use crate::snapshot::SnapshotItem;
+use serde::{Deserialize, Serialize};
+use wascc_codec::deserialize;
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const MAGIC: &[u8; 4] = b"TKVD";
+const VERSION: u32 = 1;
+const COMPACT_MIN_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+enum DiskRecord {
+    Put {
+        key: String,
+        item: SnapshotItem,
+        expires_at: Option<u64>,
+    },
+    Del {
+        key: String,
+    },
+    Expire {
+        key: String,
+        at: Option<u64>,
+    },
+}
+
+/// Where the payload of the latest `Put` of a key starts and how long it is
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    offset: u64,
+    len: u32,
+}
+
+impl Location {
+    fn frame_len(&self) -> u64 {
+        FRAME_LEN as u64 + u64::from(self.len)
+    }
+}
+
+pub struct DiskBackend {
+    path: PathBuf,
+    /// Reads seek, so even they need exclusive access to the file
+    file: Mutex<File>,
+    /// Where the latest value of each key is and its type, so the type can be
+    /// told without reading the value. Ordered so that scans can read the keys
+    /// a page at a time.
+    index: BTreeMap<String, (Location, ItemType)>,
+    expirations: HashMap<String, u64>,
+    size: u64,
+    /// Bytes taken by the records the index points at
+    live_bytes: u64,
+    compact_min_bytes: u64,
+    unsynced: bool,
+}
+
+impl DiskBackend {
+    /// Opens the data file at `path`, creating it if it does not exist, and
+    /// indexes it. The file is streamed through rather than read into memory,
+    /// so only the index has to fit. A truncated or corrupt tail is cut off.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        let file_len = file.metadata()?.len();
+        if file_len == 0 {
+            file.write_all(&frame::header(MAGIC, VERSION))?;
+            file.sync_all()?;
+        }
+
+        let mut backend = DiskBackend {
+            path,
+            file: Mutex::new(file.try_clone()?),
+            index: BTreeMap::new(),
+            expirations: HashMap::new(),
+            size: HEADER_LEN,
+            live_bytes: 0,
+            compact_min_bytes: COMPACT_MIN_BYTES,
+            unsynced: false,
+        };
+        if file_len == 0 {
+            return Ok(backend);
+        }
+        let mut reader = BufReader::new(file);
+        let mut header = Vec::new();
+        reader.by_ref().take(HEADER_LEN).read_to_end(&mut header)?;
+        frame::check_header(&header, MAGIC, VERSION, VERSION, "data file")?;
+        while let Some((len, record)) = frame::read::<DiskRecord, _>(&mut reader) {
+            let location = Location {
+                offset: backend.size + FRAME_LEN as u64,
+                len,
+            };
+            backend.size += location.frame_len();
+            backend.index_record(location, record);
+        }
+        if backend.size < file_len {
+            warn!(
+                "Discarding {} bytes of truncated or corrupt records at the end of {:?}",
+                file_len - backend.size,
+                backend.path
+            );
+            let file = backend.file.get_mut().unwrap();
+            file.set_len(backend.size)?;
+            file.sync_all()?;
+        }
+        Ok(backend)
+    }
+
+    /// Brings the index up to date with a record stored at `location`
+    fn index_record(&mut self, location: Location, record: DiskRecord) {
+        match record {
+            DiskRecord::Put {
+                key,
+                item,
+                expires_at,
+            } => {
+                self.live_bytes += location.frame_len();
+                if let Some((old, _)) = self.index.insert(key.clone(), (location, item.item_type())) {
+                    self.live_bytes -= old.frame_len();
+                }
+                self.index_expiry(key, expires_at);
+            }
+            DiskRecord::Del { key } => {
+                if let Some((old, _)) = self.index.remove(&key) {
+                    self.live_bytes -= old.frame_len();
+                }
+                self.expirations.remove(&key);
+            }
+            DiskRecord::Expire { key, at } => self.index_expiry(key, at),
+        }
+    }
+
+    fn index_expiry(&mut self, key: String, at: Option<u64>) {
+        match at {
+            Some(at) => self.expirations.insert(key, at),
+            None => self.expirations.remove(&key),
+        };
+    }
+
+    fn append(&mut self, record: DiskRecord) -> Result<(), Box<dyn Error>> {
+        let frame = frame(&record)?;
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(self.size))?;
+            file.write_all(&frame)?;
+        }
+        let location = Location {
+            offset: self.size + FRAME_LEN as u64,
+            len: (frame.len() - FRAME_LEN) as u32,
+        };
+        self.size += frame.len() as u64;
+        self.unsynced = true;
+        self.index_record(location, record);
+        Ok(())
+    }
+
+    fn read(&self, location: Location) -> Result<DiskRecord, Box<dyn Error>> {
+        let mut buf = vec![0u8; location.len as usize];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(location.offset))?;
+        file.read_exact(&mut buf)?;
+        deserialize(&buf)
+    }
+
+    fn read_item(&self, location: Location) -> Result<SnapshotItem, Box<dyn Error>> {
+        match self.read(location)? {
+            DiskRecord::Put { item, .. } => Ok(item),
+            _ => Err(format!("Corrupt index for {:?}", self.path).into()),
+        }
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.size >= self.compact_min_bytes && self.size - HEADER_LEN - self.live_bytes > self.live_bytes
+    }
+
+    /// Rewrites the data file with one `Put` per live key
+    fn compact(&mut self) -> Result<(), Box<dyn Error>> {
+        let tmp = temp_path(&self.path);
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        let header = frame::header(MAGIC, VERSION);
+        file.write_all(&header)?;
+        let mut size = header.len() as u64;
+        let mut index = BTreeMap::new();
+        for (key, (location, item_type)) in self.index.iter() {
+            let frame = frame(&DiskRecord::Put {
+                key: key.clone(),
+                item: self.read_item(*location)?,
+                expires_at: self.expiry(key),
+            })?;
+            let location = Location {
+                offset: size + FRAME_LEN as u64,
+                len: (frame.len() - FRAME_LEN) as u32,
+            };
+            index.insert(key.clone(), (location, *item_type));
+            file.write_all(&frame)?;
+            size += frame.len() as u64;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        replace(&tmp, &self.path)?;
+
+        let before = self.size;
+        self.file = Mutex::new(OpenOptions::new().read(true).write(true).open(&self.path)?);
+        self.index = index;
+        self.size = size;
+        self.live_bytes = size - HEADER_LEN;
+        self.unsynced = false;
+        info!("Compacted {:?} from {} to {} bytes", self.path, before, self.size);
+        Ok(())
+    }
+}
+
+impl Backend for DiskBackend {
+    fn name(&self) -> &'static str {
+        DISK_STORAGE
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Cow<'_, KeyValueItem>>, Box<dyn Error>> {
+        match self.index.get(key) {
+            Some((location, _)) => Ok(Some(Cow::Owned(self.read_item(*location)?.into()))),
+            None => Ok(None),
+        }
+    }
+
+    fn item_type(&self, key: &str) -> Result<Option<ItemType>, Box<dyn Error>> {
+        Ok(self.index.get(key).map(|(_, item_type)| *item_type))
+    }
+
+    fn update(
+        &mut self,
+        key: &str,
+        f: &mut dyn FnMut(&mut Option<KeyValueItem>) -> bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut slot = self.get(key)?.map(Cow::into_owned);
+        let existed = slot.is_some();
+        if !f(&mut slot) {
+            return Ok(());
+        }
+        match slot {
+            Some(item) => self.append(DiskRecord::Put {
+                key: key.to_string(),
+                item: (&item).into(),
+                expires_at: self.expiry(key),
+            }),
+            None if existed => self.remove(key),
+            None => Ok(()),
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), Box<dyn Error>> {
+        if self.index.contains_key(key) || self.expirations.contains_key(key) {
+            self.append(DiskRecord::Del { key: key.to_string() })?;
+        }
+        Ok(())
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.index.keys().cloned().collect()
+    }
+
+    fn keys_after(&self, prefix: &str, after: Option<&str>, count: usize) -> Vec<String> {
+        ordered_keys_after(&self.index, prefix, after, count)
+    }
+
+    fn expiry(&self, key: &str) -> Option<u64> {
+        self.expirations.get(key).cloned()
+    }
+
+    fn set_expiry(&mut self, key: &str, at: Option<u64>) -> Result<(), Box<dyn Error>> {
+        if self.expiry(key) != at {
+            self.append(DiskRecord::Expire {
+                key: key.to_string(),
+                at,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn expired(&self, now: u64) -> Vec<String> {
+        self.expirations
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// Syncs whatever was appended since the last call and compacts the file
+    /// once most of it is garbage
+    fn maintain(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.unsynced {
+            self.file.lock().unwrap().sync_data()?;
+            self.unsynced = false;
+        }
+        if self.needs_compaction() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskBackend {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.lock().unwrap().sync_data() {
+            error!("Syncing {:?} failed: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DiskBackend;
+    use crate::backend::Backend;
+    use crate::kv::{now_millis, ItemType, KeyValueItem, KeyValueStore, TTL_NO_KEY};
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("tea-kvp-{}-{}.data", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn open(path: &PathBuf) -> KeyValueStore {
+        KeyValueStore::with_backend(Box::new(DiskBackend::open(path).unwrap()))
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = temp_path("reopen");
+        {
+            let mut store = open(&path);
+            store.incr("counter", 5).unwrap();
+            store.set("scalar", "value".to_owned().into_bytes()).unwrap();
+            store.expire("scalar", 60_000).unwrap();
+            store.rpush("list", vec![b"a".to_vec()]).unwrap();
+            store.rpush("list", vec![b"b".to_vec()]).unwrap();
+            store.sadd("set", b"x".to_vec()).unwrap();
+            store.sv_insert("sorted", &(2, b"two".to_vec()), false).unwrap();
+            store.set("gone", b"soon".to_vec()).unwrap();
+            store.del("gone").unwrap();
+        }
+
+        let mut store = open(&path);
+        assert_eq!(6, store.incr("counter", 1).unwrap());
+        assert_eq!("value".to_owned().into_bytes(), store.get("scalar").unwrap());
+        assert!(store.ttl("scalar").unwrap() > 0);
+        assert_eq!(vec![b"a".to_vec(), b"b".to_vec()], store.lrange("list", 0, 10).unwrap());
+        assert_eq!(vec![b"x".to_vec()], store.smembers("set".to_string()).unwrap());
+        assert_eq!(vec![(2, b"two".to_vec())], store.sv_into_vec("sorted").unwrap());
+        assert_eq!(false, store.exists("gone").unwrap());
+        assert_eq!(TTL_NO_KEY, store.ttl("gone").unwrap());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_collections_survive_a_crash() {
+        let path = temp_path("crash");
+        let mut store = open(&path);
+        store.rpush("list", vec![b"a".to_vec()]).unwrap();
+        store.hset("hash", vec![("f".to_string(), b"1".to_vec())]).unwrap();
+        store.zadd("ranked", vec![(b"bob".to_vec(), 3)]).unwrap();
+        store.sadd("set", b"x".to_vec()).unwrap();
+        // Nothing gets to run on the way down
+        std::mem::forget(store);
+
+        let store = open(&path);
+        assert_eq!(vec![b"a".to_vec()], store.lrange("list", 0, -1).unwrap());
+        assert_eq!(1, store.hlen("hash").unwrap());
+        assert_eq!(1, store.zcard("ranked").unwrap());
+        assert!(store.sismember("set", b"x").unwrap());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_scan_after_reopen() {
+        let path = temp_path("scan");
+        {
+            let mut store = open(&path);
+            for key in &["b", "a", "c", "ab"] {
+                store.set(key, vec![]).unwrap();
+            }
+        }
+        let store = open(&path);
+        let (keys, cursor) = store.scan("", Some("a"), 2, None, None).unwrap();
+        assert_eq!(vec!["ab".to_string(), "b".to_string()], keys);
+        assert_eq!(Some("b".to_string()), cursor);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_scan_by_type_reads_no_values() {
+        let path = temp_path("scan-type");
+        let mut backend = DiskBackend::open(&path).unwrap();
+        let hash = (0..100).map(|i| (i.to_string(), vec![i as u8])).collect();
+        backend.put("hash", KeyValueItem::Hash(hash)).unwrap();
+        backend.put("scalar", KeyValueItem::Scalar(b"value".to_vec())).unwrap();
+        // 0xc1 is never used by msgpack, so the hash can no longer be decoded
+        let (location, _) = backend.index["hash"];
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(location.offset)).unwrap();
+        file.write_all(&vec![0xc1; location.len as usize]).unwrap();
+
+        let store = KeyValueStore::with_backend(Box::new(backend));
+        let (keys, _) = store.scan("", None, 10, None, Some(ItemType::Hash)).unwrap();
+        assert_eq!(vec!["hash".to_string()], keys);
+        assert!(store.hlen("hash").is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_truncated_tail() {
+        let path = temp_path("truncated");
+        {
+            let mut store = open(&path);
+            store.set("kept", b"1".to_vec()).unwrap();
+            store.set("torn", b"2".to_vec()).unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let mut store = open(&path);
+        assert_eq!(b"1".to_vec(), store.get("kept").unwrap());
+        assert_eq!(false, store.exists("torn").unwrap());
+        store.set("after", b"3".to_vec()).unwrap();
+        drop(store);
+        assert_eq!(b"3".to_vec(), open(&path).get("after").unwrap());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let path = temp_path("bad-header");
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"not a data file")
+            .unwrap();
+        assert!(DiskBackend::open(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let path = temp_path("compaction");
+        let mut backend = DiskBackend::open(&path).unwrap();
+        backend.compact_min_bytes = 0;
+        let mut store = KeyValueStore::with_backend(Box::new(backend));
+        for i in 0..100 {
+            store.incr("counter", 1).unwrap();
+            store.set(&format!("key{}", i % 10), vec![i as u8; 100]).unwrap();
+        }
+        store.expire_at("key0", now_millis() + 60_000).unwrap();
+        let before = fs::metadata(&path).unwrap().len();
+        store.maintain().unwrap();
+        let after = fs::metadata(&path).unwrap().len();
+        assert!(after < before / 5);
+
+        store.set("key1", b"fresh".to_vec()).unwrap();
+        drop(store);
+        let mut store = open(&path);
+        assert_eq!(101, store.incr("counter", 1).unwrap());
+        assert_eq!(vec![99u8; 100], store.get("key9").unwrap());
+        assert_eq!(b"fresh".to_vec(), store.get("key1").unwrap());
+        assert!(store.ttl("key0").unwrap() > 0);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_expiry_is_indexed() {
+        let path = temp_path("expiry");
+        let mut backend = DiskBackend::open(&path).unwrap();
+        backend.set_expiry("missing", Some(1)).unwrap();
+        backend.remove("missing").unwrap();
+        assert!(backend.expired(now_millis()).is_empty());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,104 @@
+//! Errors returned to actors. Every failure of a `tea:keyvalue` call reaches
+//! the actor as a message of the form `CODE: detail`, where `CODE` is one of
+//! the `ERR_*` constants in `protocol` and never changes between versions.
+
+use crate::kv::ItemType;
+use crate::protocol;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum KvError {
+    NoSuchKey,
+    /// The key holds a different type of item than the operation works on
+    WrongType {
+        expected: ItemType,
+        actual: ItemType,
+    },
+    OutOfRange(String),
+    /// The request could not be decoded into the message its operation expects
+    DecodeError(String),
+    /// The request decoded but asks for something its operation doesn't allow
+    InvalidRequest(String),
+    UnknownOp(String),
+    QuotaExceeded(String),
+    /// A watched key changed since the caller read its version
+    Conflict(String),
+    /// Anything else, such as a failing storage backend
+    Internal(String),
+}
+
+impl KvError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            KvError::NoSuchKey => protocol::ERR_NO_SUCH_KEY,
+            KvError::WrongType { .. } => protocol::ERR_WRONG_TYPE,
+            KvError::OutOfRange(_) => protocol::ERR_OUT_OF_RANGE,
+            KvError::DecodeError(_) => protocol::ERR_DECODE,
+            KvError::InvalidRequest(_) => protocol::ERR_INVALID_REQUEST,
+            KvError::UnknownOp(_) => protocol::ERR_UNKNOWN_OP,
+            KvError::QuotaExceeded(_) => protocol::ERR_QUOTA_EXCEEDED,
+            KvError::Conflict(_) => protocol::ERR_CONFLICT,
+            KvError::Internal(_) => protocol::ERR_INTERNAL,
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.code())?;
+        match self {
+            KvError::NoSuchKey => write!(f, "no such key"),
+            KvError::WrongType { expected, actual } => {
+                write!(f, "expected {} but the key holds {}", expected, actual)
+            }
+            KvError::UnknownOp(op) => write!(f, "unknown operation {}", op),
+            KvError::OutOfRange(detail)
+            | KvError::DecodeError(detail)
+            | KvError::InvalidRequest(detail)
+            | KvError::QuotaExceeded(detail)
+            | KvError::Conflict(detail)
+            | KvError::Internal(detail) => write!(f, "{}", detail),
+        }
+    }
+}
+
+impl Error for KvError {}
+
+/// Keeps errors that already are a `KvError` and wraps everything else as internal
+impl From<Box<dyn Error>> for KvError {
+    fn from(e: Box<dyn Error>) -> Self {
+        match e.downcast::<KvError>() {
+            Ok(e) => *e,
+            Err(e) => KvError::Internal(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KvError;
+    use crate::kv::ItemType;
+    use std::error::Error;
+
+    #[test]
+    fn test_wire_format() {
+        let e = KvError::WrongType {
+            expected: ItemType::List,
+            actual: ItemType::Set,
+        };
+        assert_eq!("WRONG_TYPE: expected list but the key holds set", e.to_string());
+        assert_eq!("NO_SUCH_KEY: no such key", KvError::NoSuchKey.to_string());
+        assert_eq!("UNKNOWN_OP: unknown operation Foo", KvError::UnknownOp("Foo".into()).to_string());
+        let e = KvError::InvalidRequest("Exec can't contain another Exec".into());
+        assert_eq!("INVALID_REQUEST: Exec can't contain another Exec", e.to_string());
+    }
+
+    #[test]
+    fn test_from_boxed() {
+        let boxed: Box<dyn Error> = Box::new(KvError::NoSuchKey);
+        assert_eq!(KvError::NoSuchKey, KvError::from(boxed));
+        let boxed: Box<dyn Error> = "disk full".into();
+        assert_eq!(KvError::Internal("disk full".into()), KvError::from(boxed));
+    }
+}
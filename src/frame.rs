@@ -0,0 +1,183 @@
+//! Framing shared by the files this provider appends to.
+//!
+//! A file starts with a 4 byte magic and a little endian format version. Each
+//! record after that is framed as a little endian `u32` payload length, a
+//! CRC-32 of the payload and the msgpack encoded payload itself. A record that
+//! is cut short, fails its checksum or does not decode marks the end of the
+//! usable file, which is what a crash in the middle of an append leaves behind.
+
+use crate::error::KvError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wascc_codec::{deserialize, serialize};
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+pub const HEADER_LEN: u64 = 8;
+pub const FRAME_LEN: usize = 8;
+
+pub fn header(magic: &[u8; 4], version: u32) -> Vec<u8> {
+    let mut buf = magic.to_vec();
+    buf.extend_from_slice(&version.to_le_bytes());
+    buf
+}
+
+/// Fails unless `buf` starts with the given magic and a version from
+/// `min_version` to `version`. `what` names the kind of file in the error.
+pub fn check_header(
+    buf: &[u8],
+    magic: &[u8; 4],
+    min_version: u32,
+    version: u32,
+    what: &str,
+) -> Result<(), Box<dyn Error>> {
+    let header_len = HEADER_LEN as usize;
+    if buf.len() < header_len || &buf[..4] != magic {
+        return Err(format!("Not a key-value {}", what).into());
+    }
+    let found = read_u32(&buf[4..header_len]);
+    if found < min_version || found > version {
+        return Err(format!("Unsupported {} version {}", what, found).into());
+    }
+    Ok(())
+}
+
+pub fn frame<T: Serialize>(record: &T) -> Result<Vec<u8>, Box<dyn Error>> {
+    let payload = serialize(record)?;
+    if payload.len() > u32::MAX as usize {
+        return Err(KvError::QuotaExceeded(format!("record of {} bytes is too large to store", payload.len())).into());
+    }
+    let mut buf = Vec::with_capacity(FRAME_LEN + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32(&payload).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    Ok(buf)
+}
+
+/// Returns every intact record after the header together with the range its
+/// payload occupies in `buf`, and the length of the valid prefix of `buf`.
+/// The header has to be checked by the caller.
+pub fn decode<T: DeserializeOwned>(buf: &[u8]) -> (Vec<(Range<usize>, T)>, u64) {
+    let mut records = Vec::new();
+    let mut pos = HEADER_LEN as usize;
+    let mut reader = buf.get(pos..).unwrap_or_default();
+    while let Some((len, record)) = read(&mut reader) {
+        let start = pos + FRAME_LEN;
+        records.push((start..start + len as usize, record));
+        pos = start + len as usize;
+    }
+    (records, pos as u64)
+}
+
+/// Reads the next record and the length of its payload from `reader`, which
+/// has to be positioned at the start of a frame. Returns `None` at the end of
+/// the usable file.
+pub fn read<T: DeserializeOwned, R: Read>(reader: &mut R) -> Option<(u32, T)> {
+    let mut head = [0u8; FRAME_LEN];
+    reader.read_exact(&mut head).ok()?;
+    let len = read_u32(&head[..4]);
+    let crc = read_u32(&head[4..]);
+    // Grows with what is actually there rather than trusting a damaged length
+    let mut payload = Vec::new();
+    reader.by_ref().take(u64::from(len)).read_to_end(&mut payload).ok()?;
+    if payload.len() != len as usize || crc32(&payload) != crc {
+        return None;
+    }
+    deserialize(&payload).ok().map(|record| (len, record))
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(buf);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// Renames `tmp` over `path` and syncs the directory holding them, so the
+/// rename survives a machine crash too. Not every platform can open a
+/// directory for that, and the file is in place either way, so a failure to
+/// sync the directory is ignored.
+pub fn replace(tmp: &Path, path: &Path) -> io::Result<()> {
+    fs::rename(tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// CRC-32 (IEEE), computed bitwise. Records are small and files are only
+/// checked as a whole once at startup, so a lookup table is not worth it.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_header, crc32, frame, header, read, replace, temp_path};
+    use std::fs;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+        assert_eq!(0, crc32(b""));
+    }
+
+    #[test]
+    fn test_check_header() {
+        assert!(check_header(&header(b"TEST", 2), b"TEST", 1, 3, "file").is_ok());
+        assert!(check_header(&header(b"TEST", 1), b"TEST", 1, 3, "file").is_ok());
+        assert!(check_header(&header(b"TEST", 0), b"TEST", 1, 3, "file").is_err());
+        assert!(check_header(&header(b"TEST", 4), b"TEST", 1, 3, "file").is_err());
+        assert!(check_header(&header(b"NOPE", 2), b"TEST", 1, 3, "file").is_err());
+        assert!(check_header(b"TEST", b"TEST", 1, 3, "file").is_err());
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("tea-kvp-replace-{}", std::process::id()));
+        fs::write(&path, b"old").unwrap();
+        let tmp = temp_path(&path);
+        fs::write(&tmp, b"new").unwrap();
+        replace(&tmp, &path).unwrap();
+        assert_eq!(b"new".to_vec(), fs::read(&path).unwrap());
+        assert!(!tmp.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read() {
+        let mut buf = frame(&"first".to_string()).unwrap();
+        buf.extend(frame(&"second".to_string()).unwrap());
+        let mut reader = &buf[..];
+        assert_eq!(Some((6, "first".to_string())), read(&mut reader));
+        assert_eq!(Some((7, "second".to_string())), read(&mut reader));
+        assert_eq!(None, read::<String, _>(&mut reader));
+
+        // A damaged length must not be trusted with an allocation
+        let mut damaged = frame(&"third".to_string()).unwrap();
+        damaged[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(None, read::<String, _>(&mut &damaged[..]));
+    }
+}
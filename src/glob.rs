@@ -0,0 +1,130 @@
+//! Glob patterns for matching keys, as used by `Scan`. `*` matches any run of
+//! characters, `?` any one character, `[abc]` and `[a-z]` one character of a
+//! set and `[!abc]` one character outside of it. `\` makes the character
+//! after it match only itself.
+
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to pick up again if what follows the last `*` fails to match:
+    // the pattern after the `*` and the text it was tried against
+    let mut retry: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            retry = Some((p, t));
+            continue;
+        }
+        if let Some(next) = match_one(&pattern, p, text[t]) {
+            p = next;
+            t += 1;
+            continue;
+        }
+        match retry {
+            // Let the `*` swallow one more character and try again
+            Some((after_star, tried)) => {
+                p = after_star;
+                t = tried + 1;
+                retry = Some((after_star, t));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// If the pattern element starting at `p` matches `c`, where the next element starts
+fn match_one(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match pattern.get(p)? {
+        '?' => Some(p + 1),
+        '\\' if p + 1 < pattern.len() => literal(pattern[p + 1] == c, p + 2),
+        '[' => match class_end(pattern, p) {
+            Some(end) => literal(class_matches(&pattern[p + 1..end], c), end + 1),
+            // An unterminated `[` is just a character
+            None => literal(c == '[', p + 1),
+        },
+        other => literal(*other == c, p + 1),
+    }
+}
+
+fn literal(matched: bool, next: usize) -> Option<usize> {
+    if matched {
+        Some(next)
+    } else {
+        None
+    }
+}
+
+/// The position of the `]` closing the class that opens at `start`. A `]`
+/// right after the opening `[` or `[!` belongs to the class.
+fn class_end(pattern: &[char], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    if pattern.get(i) == Some(&'!') {
+        i += 1;
+    }
+    if pattern.get(i) == Some(&']') {
+        i += 1;
+    }
+    while i < pattern.len() {
+        match pattern[i] {
+            '\\' => i += 2,
+            ']' => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Whether `c` is in the class written between the brackets
+fn class_matches(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.split_first() {
+        Some(('!', rest)) => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        let low = if class[i] == '\\' && i + 1 < class.len() {
+            i += 1;
+            class[i]
+        } else {
+            class[i]
+        };
+        if class.get(i + 1) == Some(&'-') && i + 2 < class.len() {
+            found |= low <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            found |= low == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod test {
+    use super::matches;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("user:*:session", "user:42:session"));
+        assert!(matches("user:*:session", "user::session"));
+        assert!(matches("user:*:session", "user:a:b:session"));
+        assert!(!matches("user:*:session", "user:42:sessions"));
+        assert!(matches("*", ""));
+        assert!(matches("**a*", "banana"));
+        assert!(!matches("", "a"));
+        assert!(matches("h?llo", "héllo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[!ae]llo", "hillo"));
+        assert!(matches("v[0-9]", "v7"));
+        assert!(!matches("v[0-9]", "vx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("a\\*", "a*"));
+        assert!(!matches("a\\*", "ab"));
+        assert!(matches("[a", "[a"));
+    }
+}
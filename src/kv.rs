@@ -1,376 +1,2412 @@
-use std::collections::HashMap;
-use std::collections::HashSet;
-use key_vec::KeyVec;
-use std::error::Error;
+use crate::backend::{Backend, MemoryBackend};
+use crate::error::KvError;
+use crate::glob;
+use crate::protocol::{ListEnd, SetCondition};
+use crate::sorted_vec::{SortedVec, F64};
+use crate::zset::SortedSet;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::{Bound, Range};
+use std::sync::Mutex;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use rand::seq::index;
+use rand::{RngCore, SeedableRng};
 use std::result::Result;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+#[derive(Clone)]
 pub enum KeyValueItem {
-    Atomic(i32),
+    Atomic(i64),
+    Float(f64),
     Scalar(Vec<u8>),
-    List(Vec<Vec<u8>>),
-    Set(HashSet<Vec<u8>>),
-    SortedVec(KeyVec<i32, Vec<u8>>),
+    List(VecDeque<Vec<u8>>),
+    /// Ordered, so random picks index the same members whatever order they
+    /// were added in
+    Set(BTreeSet<Vec<u8>>),
+    SortedVec(SortedVec<i32>),
+    SortedVecI64(SortedVec<i64>),
+    SortedVecU64(SortedVec<u64>),
+    SortedVecF64(SortedVec<F64>),
+    SortedSet(SortedSet),
+    Hash(BTreeMap<String, Vec<u8>>),
 }
 
+impl KeyValueItem {
+    pub fn item_type(&self) -> ItemType {
+        match self {
+            KeyValueItem::Atomic(_) => ItemType::Atomic,
+            KeyValueItem::Float(_) => ItemType::Float,
+            KeyValueItem::Scalar(_) => ItemType::Scalar,
+            KeyValueItem::List(_) => ItemType::List,
+            KeyValueItem::Set(_) => ItemType::Set,
+            KeyValueItem::SortedVec(_) => ItemType::SortedVec,
+            KeyValueItem::SortedVecI64(_) => ItemType::SortedVecI64,
+            KeyValueItem::SortedVecU64(_) => ItemType::SortedVecU64,
+            KeyValueItem::SortedVecF64(_) => ItemType::SortedVecF64,
+            KeyValueItem::SortedSet(_) => ItemType::SortedSet,
+            KeyValueItem::Hash(_) => ItemType::Hash,
+        }
+    }
+}
+
+/// Serialized under the names `Display` gives, the ones `WRONG_TYPE` errors use
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemType {
+    Atomic,
+    Float,
+    Scalar,
+    List,
+    Set,
+    /// A sorted vec with `i32` scores, the type the original `KeyVec*` operations work on
+    SortedVec,
+    SortedVecI64,
+    SortedVecU64,
+    SortedVecF64,
+    SortedSet,
+    Hash,
+}
+
+impl fmt::Display for ItemType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ItemType::Atomic => "atomic",
+            ItemType::Float => "float",
+            ItemType::Scalar => "scalar",
+            ItemType::List => "list",
+            ItemType::Set => "set",
+            ItemType::SortedVec => "sorted_vec",
+            ItemType::SortedVecI64 => "sorted_vec_i64",
+            ItemType::SortedVecU64 => "sorted_vec_u64",
+            ItemType::SortedVecF64 => "sorted_vec_f64",
+            ItemType::SortedSet => "sorted_set",
+            ItemType::Hash => "hash",
+        })
+    }
+}
+
+fn wrong_type(expected: ItemType, item: &KeyValueItem) -> KvError {
+    KvError::WrongType {
+        expected,
+        actual: item.item_type(),
+    }
+}
+
+/// A score type sorted vecs can be keyed by. Each score type is an item type
+/// of its own, so a key keeps the score type it was created with and every
+/// operation has to agree with it.
+pub trait Score: Copy + Ord {
+    const ITEM_TYPE: ItemType;
+    fn sorted_vec(item: &KeyValueItem) -> Option<&SortedVec<Self>>;
+    fn sorted_vec_mut(item: &mut KeyValueItem) -> Option<&mut SortedVec<Self>>;
+    fn into_item(kvec: SortedVec<Self>) -> KeyValueItem;
+}
+
+macro_rules! score {
+    ($score:ty, $variant:ident) => {
+        impl Score for $score {
+            const ITEM_TYPE: ItemType = ItemType::$variant;
+
+            fn sorted_vec(item: &KeyValueItem) -> Option<&SortedVec<Self>> {
+                match item {
+                    KeyValueItem::$variant(kvec) => Some(kvec),
+                    _ => None,
+                }
+            }
+
+            fn sorted_vec_mut(item: &mut KeyValueItem) -> Option<&mut SortedVec<Self>> {
+                match item {
+                    KeyValueItem::$variant(kvec) => Some(kvec),
+                    _ => None,
+                }
+            }
+
+            fn into_item(kvec: SortedVec<Self>) -> KeyValueItem {
+                KeyValueItem::$variant(kvec)
+            }
+        }
+    };
+}
+
+score!(i32, SortedVec);
+score!(i64, SortedVecI64);
+score!(u64, SortedVecU64);
+score!(F64, SortedVecF64);
+
 pub struct KeyValueStore {
-    items: HashMap<String, KeyValueItem>,
+    items: Box<dyn Backend>,
+    /// Number of write operations applied since the store was created
+    write_count: u64,
+    /// Sequence number of the last operation log record reflected in the store
+    seq: u64,
+    /// Picks the members returned by `spop` and `srandmember`
+    rng: Mutex<Box<dyn RngCore + Send>>,
+    /// While a transaction runs, every key it wrote to as it was before the
+    /// first write
+    journal: Option<HashMap<String, Saved>>,
+    /// Version of every key written since the store was created
+    versions: HashMap<String, u64>,
+    /// Version of the keys the store was loaded with. Versions count from the
+    /// creation time of the store in millionths of a millisecond, so versions
+    /// handed out before a restart are not handed out again.
+    first_version: u64,
+    last_version: u64,
+    /// While set, the time in milliseconds since the unix epoch that expiry is
+    /// judged against instead of the wall clock. Pinned to the time a write is
+    /// applied at, and to that same time while the operation log replays it,
+    /// so replay reclaims exactly the keys the original write did.
+    clock: Option<u64>,
+}
+
+/// A key as it was before the running transaction first wrote to it
+struct Saved {
+    item: Option<KeyValueItem>,
+    expires_at: Option<u64>,
+    /// Its entry in `versions`, so a rollback doesn't count as a write to it
+    version: Option<u64>,
+}
+
+/// Returned by `ttl` for a key that does not exist
+pub const TTL_NO_KEY: i64 = -2;
+/// Returned by `ttl` for a key that exists but has no expiry
+pub const TTL_NO_EXPIRY: i64 = -1;
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
 }
 
 impl KeyValueStore {
     pub fn new() -> Self {
+        Self::with_backend(Box::new(MemoryBackend::new()))
+    }
+
+    pub fn with_backend(items: Box<dyn Backend>) -> Self {
         KeyValueStore {
-            items: HashMap::new(),
+            items,
+            write_count: 0,
+            seq: 0,
+            rng: Mutex::new(Box::new(StdRng::from_entropy())),
+            journal: None,
+            versions: HashMap::new(),
+            first_version: now_millis().saturating_mul(1_000_000),
+            last_version: now_millis().saturating_mul(1_000_000),
+            clock: None,
         }
     }
 
-    pub fn incr(&mut self, key: &str, value: i32) -> Result<i32, Box<dyn Error>> {
-        let mut orig = 0;
-        self.items
-            .entry(key.to_string())
-            .and_modify(|v| {
-                if let KeyValueItem::Atomic(ref x) = v {
-                    orig = *x;
-                    *v = KeyValueItem::Atomic(x + value);
-                }
-            })
-            .or_insert(KeyValueItem::Atomic(value));
-        Ok(orig + value)
+    /// Replaces the random number generator, so tests can seed it
+    #[cfg(test)]
+    pub fn set_rng(&mut self, rng: Box<dyn RngCore + Send>) {
+        self.rng = Mutex::new(rng);
+    }
+
+    /// Runs `f` with the store's clock pinned to `now`
+    pub fn at<R>(&mut self, now: u64, f: impl FnOnce(&mut Self) -> R) -> R {
+        let previous = self.clock.replace(now);
+        let result = f(self);
+        self.clock = previous;
+        result
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.unwrap_or_else(now_millis)
+    }
+
+    pub fn backend_name(&self) -> &'static str {
+        self.items.name()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.keys().is_empty()
     }
 
-    pub fn del(&mut self, key: &str) -> Result<(), Box<dyn Error>> {
-        self.items.remove(key);
+    /// Removes every key. Does not count as a write.
+    pub fn clear(&mut self) -> Result<(), KvError> {
+        for key in self.items.keys() {
+            self.remove(&key)?;
+        }
         Ok(())
     }
 
-    pub fn exists(&self, key: &str) -> Result<bool, Box<dyn Error>> {
-        Ok(self.items.contains_key(key))
+    pub fn write_count(&self) -> u64 {
+        self.write_count
     }
 
-    pub fn get(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-        self.items.get(key).map_or_else(
-            || Err("No such key".into()),
-            |v| {
-                if let KeyValueItem::Scalar(ref s) = v {
-                    Ok(s.clone())
-                } else {
-                    Err("Attempt to fetch non-scalar".into())
-                }
-            },
-        )
+    pub fn seq(&self) -> u64 {
+        self.seq
     }
 
-    pub fn lrange(&self, key: &str, start: i32, stop: i32) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
-        let start = start.max(0);
-        self.items.get(key).map_or_else(
-            || Ok(vec![vec![]]),
-            |v| {
-                if let KeyValueItem::List(l) = v {
-                    let stop = stop.min(l.len() as _);
-                    Ok(l.as_slice()[start as _..stop as _].to_vec())
-                } else {
-                    Err("Attempt to fetch non-list".into())
-                }
-            },
-        )
+    pub fn set_seq(&mut self, seq: u64) {
+        self.seq = seq;
     }
 
-    pub fn lpush(&mut self, key: &str, value: Vec<u8>) -> Result<i32, Box<dyn Error>> {
-        let mut len = 1;
-        self.items
-            .entry(key.to_string())
-            .and_modify(|v| {
-                if let KeyValueItem::List(ref l) = v {
-                    let mut list = Vec::new();
-                    list.extend_from_slice(&l);
-                    list.push(value.clone());
-                    len = list.len();
-                    *v = KeyValueItem::List(list);
-                }
-            })
-            .or_insert_with(|| KeyValueItem::List(vec![value]));
-        Ok(len as _)
-    }
-
-    pub fn sv_insert(&mut self, key:&str, value: &(i32, Vec<u8>), overwrite: bool)-> Result<bool, Box<dyn Error>> {
-        let mut result = false;
-        self.items
-            .entry(key.to_string())
-            .and_modify(|v| {
-                if let KeyValueItem::SortedVec(ref mut kvec) = v {
-                    if let Some(_current_existing_value) = kvec.get(&value.0){
-                        if overwrite {
-                            kvec.insert(value.0, value.1.clone()); 
-                            result = true;
-                        }
-                        else{
-                            result = false;
-                        }
-                    }
-                    else{
-                        kvec.insert(value.0, value.1.clone());
-                        result = true;
-                    }
-                }
-            })
-            .or_insert_with(|| {
-                let mut kvec = KeyVec::new();
-                kvec.insert(value.0, value.1.clone());
-                result = true;
-                KeyValueItem::SortedVec(kvec)
-            });
-        Ok(result)
-    }
-
-    pub fn sv_into_vec(&self, key: &str) -> Result<Vec<(i32, Vec<u8>)>, Box<dyn Error>> {
-        match self.items.get(key){
-            None=>Ok(Vec::new()),
-            Some(v)=>{
-                if let KeyValueItem::SortedVec(ref kvec) = v {
-                    Ok(kvec.clone().into_vec())
-                }
-                else{
-                    return Err("Attemp to call to a non Sorted Vec".into());
-                }
+    /// Calls `f` with every live key, its value and its expiry deadline. Used to take snapshots.
+    pub fn for_each_entry(
+        &self,
+        mut f: impl FnMut(&str, &KeyValueItem, Option<u64>),
+    ) -> Result<(), KvError> {
+        let now = self.now();
+        for key in self.items.keys() {
+            if self.is_expired(&key, now) {
+                continue;
+            }
+            if let Some(item) = self.items.get(&key)? {
+                f(&key, &item, self.items.expiry(&key));
             }
         }
+        Ok(())
     }
 
-    pub fn sv_tail_off(&mut self, key: &str, remain: usize) -> Result<usize, Box<dyn Error>>{
-        let mut len = 0;
-        self.items.entry(key.to_string()).and_modify(|v| {
-            if let KeyValueItem::SortedVec(ref mut kvec) = v {
-                len = kvec.len();
-                println!("kvec len remain: {},{}", len, remain);
-                if len > remain{
-                
-                    let mut i: usize = len;
-                    loop{
-                        if i == remain {
-                            break;
-                        }
-                        println!("inside loop: kvec len and i: {},{}", len, i); 
-                        kvec.remove_index(i - 1);
-                        i = i - 1;
+    /// Puts a key back as it was captured by `for_each_entry`. Does not count as a write.
+    pub fn restore(
+        &mut self,
+        key: &str,
+        item: KeyValueItem,
+        expires_at: Option<u64>,
+    ) -> Result<(), KvError> {
+        self.items.put(key, item)?;
+        self.items.set_expiry(key, expires_at)?;
+        self.bump(key);
+        Ok(())
+    }
+
+    /// Runs the backend's periodic housekeeping
+    pub fn maintain(&mut self) -> Result<(), KvError> {
+        Ok(self.items.maintain()?)
+    }
+
+    fn is_expired(&self, key: &str, now: u64) -> bool {
+        self.items.expiry(key).map_or(false, |at| at <= now)
+    }
+
+    /// Looks up a key, treating an expired one as missing. Read paths only hold
+    /// a shared reference so they hide expired keys and leave the actual
+    /// removal to the next write or the periodic `sweep`.
+    fn live(&self, key: &str) -> Result<Option<Cow<'_, KeyValueItem>>, KvError> {
+        if self.is_expired(key, self.now()) {
+            Ok(None)
+        } else {
+            Ok(self.items.get(key)?)
+        }
+    }
+
+    /// Drops the key if it has expired, so a write never resurrects stale data
+    fn reclaim(&mut self, key: &str) -> Result<(), KvError> {
+        if self.is_expired(key, self.now()) {
+            self.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the key together with its expiry and version
+    fn remove(&mut self, key: &str) -> Result<(), KvError> {
+        self.items.remove(key)?;
+        self.versions.remove(key);
+        Ok(())
+    }
+
+    /// Gives the key a new version after it was written to
+    fn bump(&mut self, key: &str) {
+        self.last_version += 1;
+        self.versions.insert(key.to_string(), self.last_version);
+    }
+
+    /// The version of the key, which grows with every write to it. A missing
+    /// key has version 0; a key that is deleted and written again gets a
+    /// version higher than any it had before.
+    pub fn version(&self, key: &str) -> Result<u64, KvError> {
+        if self.live(key)?.is_none() {
+            return Ok(0);
+        }
+        Ok(self.versions.get(key).cloned().unwrap_or(self.first_version))
+    }
+
+    /// Runs `f` as a transaction: if it fails, every key it wrote to is put
+    /// back the way it was, expiry and version included, before the error is
+    /// returned.
+    /// Transactions can't be nested.
+    pub fn transaction<R>(&mut self, f: impl FnOnce(&mut Self) -> Result<R, KvError>) -> Result<R, KvError> {
+        if self.journal.is_some() {
+            return Err(KvError::Internal("transactions can't be nested".into()));
+        }
+        self.journal = Some(HashMap::new());
+        let write_count = self.write_count;
+        let result = f(self);
+        let journal = self.journal.take().unwrap_or_default();
+        if result.is_err() {
+            for (key, saved) in journal {
+                match saved.item {
+                    Some(item) => {
+                        self.items.put(&key, item)?;
+                        self.items.set_expiry(&key, saved.expires_at)?;
                     }
-                    
+                    None => self.items.remove(&key)?,
                 }
-                len = kvec.len();
+                match saved.version {
+                    Some(version) => self.versions.insert(key, version),
+                    None => self.versions.remove(&key),
+                };
             }
-            
-        });
-        
-        Ok(len)
+            self.write_count = write_count;
+        }
+        result
     }
 
-    pub fn sv_remove_item(&mut self, key: &str, value: (i32, Vec<u8>))-> Result<bool, Box<dyn Error>>{
-        self.items
-            .entry(key.to_string())
-            .and_modify(|v| {
-                if let KeyValueItem::SortedVec(ref mut kvec) = v {
-                    if let Some(_current_existing_value) = kvec.get(&value.0){
-                        kvec.remove(&value.0);
-                    }
-                }
-            });
-        Ok(true)
+    /// Records how `key` looks before the running transaction first writes to it
+    fn save(&mut self, key: &str) -> Result<(), KvError> {
+        if let Some(journal) = self.journal.as_mut() {
+            if !journal.contains_key(key) {
+                let saved = Saved {
+                    item: self.items.get(key)?.map(Cow::into_owned),
+                    expires_at: self.items.expiry(key),
+                    version: self.versions.get(key).cloned(),
+                };
+                journal.insert(key.to_string(), saved);
+            }
+        }
+        Ok(())
     }
 
-    pub fn set(&mut self, key: &str, value: Vec<u8>) -> Result<(), Box<dyn Error>> {
-        self.items
-            .entry(key.to_string())
-            .and_modify(|v| {
-                if let KeyValueItem::Scalar(_) = v {
-                    *v = KeyValueItem::Scalar(value.clone());
-                }
-            })
-            .or_insert(KeyValueItem::Scalar(value));
+    /// Clears the expiry of a key that is about to be overwritten
+    fn clear_expiry(&mut self, key: &str) -> Result<(), KvError> {
+        if self.items.expiry(key).is_some() {
+            self.save(key)?;
+            self.items.set_expiry(key, None)?;
+        }
         Ok(())
     }
 
-    pub fn lrem(&mut self, key: &str, value: Vec<u8>) -> Result<i32, Box<dyn Error>> {
-        let mut len: i32 = 0;
-        self.items.entry(key.to_string()).and_modify(|v| {
-            if let KeyValueItem::List(ref l) = v {
-                let list: Vec<Vec<u8>> = l
-                    .iter()
-                    .filter(|i| **i != value)
-                    .map(|v| v.clone())
-                    .collect();
-                len = list.len() as _;
-                *v = KeyValueItem::List(list);
+    /// Called at the start of every mutating operation that doesn't go through
+    /// `modify`. Those count their write themselves, once they know they made one.
+    fn touch(&mut self, key: &str) -> Result<(), KvError> {
+        self.save(key)?;
+        self.reclaim(key)
+    }
+
+    /// Counts a write that changed `key` and gives the key a new version
+    fn changed(&mut self, key: &str) {
+        self.write_count += 1;
+        self.bump(key);
+    }
+
+    /// Runs `f` on the item stored at `key`, `None` if there is none. `f`
+    /// returns its result together with whether it changed the item. Only a
+    /// change is written back to the backend, counted as a write and given a
+    /// new version. When `f` fails or reports no change it must have left the
+    /// slot untouched.
+    fn modify<R>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(&mut Option<KeyValueItem>) -> Result<(R, bool), KvError>,
+    ) -> Result<R, KvError> {
+        self.save(key)?;
+        self.reclaim(key)?;
+        let mut f = Some(f);
+        let mut result = None;
+        let mut exists = false;
+        self.items.update(key, &mut |slot| match f.take() {
+            Some(f) => {
+                let r = f(slot);
+                let changed = matches!(r, Ok((_, true)));
+                result = Some(r);
+                exists = slot.is_some();
+                changed
             }
-        });
-        Ok(len)
+            None => false,
+        })?;
+        let (r, changed) = result.unwrap_or_else(|| Err(KvError::Internal("Storage backend skipped an update".into())))?;
+        if changed {
+            if exists {
+                self.changed(key);
+            } else {
+                self.write_count += 1;
+                self.versions.remove(key);
+            }
+        }
+        Ok(r)
     }
 
-    pub fn sadd(&mut self, key: &str, value: Vec<u8>) -> Result<i32, Box<dyn Error>> {
-        let mut len: i32 = 1;
-        self.items
-            .entry(key.to_string())
-            .and_modify(|v| {
-                if let KeyValueItem::Set(ref mut s) = v {
-                    s.insert(value.clone());
-                    len = s.len() as _;
-                }
-            })
-            .or_insert_with(|| new_set(value));
-        Ok(len)
-    }
-
-    pub fn srem(&mut self, key: &str, value: Vec<u8>) -> Result<i32, Box<dyn Error>> {
-        let mut len: i32 = 0;
-        self.items
-            .entry(key.to_string())
-            .and_modify(|v| {
-                if let KeyValueItem::Set(ref mut s) = v {
-                    s.remove(&value);
-                    len = s.len() as _;
-                }
-            })
-            .or_insert_with(|| KeyValueItem::Set(HashSet::new()));
-        Ok(len)
+    /// Removes every expired key and returns how many were reclaimed
+    pub fn sweep(&mut self) -> Result<usize, KvError> {
+        let expired = self.items.expired(self.now());
+        for key in expired.iter() {
+            self.remove(key)?;
+        }
+        Ok(expired.len())
     }
 
-    pub fn sunion(&self, keys: Vec<String>) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
-        let union = self
-            .items
-            .iter()
-            .filter_map(|(k, v)| {
-                if keys.contains(k) {
-                    if let KeyValueItem::Set(s) = v {
-                        Some(s.clone())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .fold(HashSet::new(), |acc, x| acc.union(&x).cloned().collect());
+    /// Sets a time to live in milliseconds. Returns false if the key does not exist.
+    pub fn expire(&mut self, key: &str, ttl_ms: u64) -> Result<bool, KvError> {
+        self.expire_at(key, self.now().saturating_add(ttl_ms))
+    }
+
+    /// Sets an absolute deadline in milliseconds since the unix epoch.
+    /// Returns false if the key does not exist.
+    pub fn expire_at(&mut self, key: &str, at_ms: u64) -> Result<bool, KvError> {
+        self.touch(key)?;
+        if self.items.item_type(key)?.is_none() {
+            return Ok(false);
+        }
+        self.items.set_expiry(key, Some(at_ms))?;
+        self.changed(key);
+        Ok(true)
+    }
 
-        Ok(union.iter().cloned().collect())
+    /// Removes the expiry of a key. Returns false if the key does not exist or had no expiry.
+    pub fn persist(&mut self, key: &str) -> Result<bool, KvError> {
+        self.touch(key)?;
+        if self.items.expiry(key).is_none() {
+            return Ok(false);
+        }
+        self.items.set_expiry(key, None)?;
+        self.changed(key);
+        Ok(true)
     }
 
-    pub fn sinter(&self, keys: Vec<String>) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
-        let sets: Vec<HashSet<Vec<u8>>> = self
+    /// Remaining time to live in milliseconds, `TTL_NO_EXPIRY` for a key without
+    /// expiry and `TTL_NO_KEY` for a missing key.
+    pub fn ttl(&self, key: &str) -> Result<i64, KvError> {
+        let now = self.now();
+        if self.live(key)?.is_none() {
+            return Ok(TTL_NO_KEY);
+        }
+        Ok(self
             .items
-            .iter()
-            .filter_map(|(k, v)| {
-                if keys.contains(k) {
-                    if let KeyValueItem::Set(s) = v {
-                        Some(s.clone())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect();
-        let set1 = &sets[0];
-        let inter = set1
-            .iter()
-            .filter(|k| sets.as_slice().iter().all(|s| s.contains(*k)));
-        Ok(inter.cloned().collect())
+            .expiry(key)
+            .map_or(TTL_NO_EXPIRY, |at| at.saturating_sub(now) as i64))
     }
 
-    pub fn smembers(&self, key: String) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
-        self.items.get(&key).map_or_else(
-            || Ok(vec![]),
+    /// Adds `value` to the counter for the original `Add` operation, which
+    /// works on `i32`s. A result that does not fit in an `i32` fails with
+    /// `OutOfRange` and leaves the counter unchanged.
+    pub fn incr(&mut self, key: &str, value: i32) -> Result<i32, KvError> {
+        let sum = self.update_counter(key, |x| {
+            x.checked_add(i64::from(value))
+                .filter(|sum| i32::try_from(*sum).is_ok())
+                .ok_or_else(|| KvError::OutOfRange(format!("adding {} to {} does not fit in an i32", value, x)))
+        })?;
+        Ok(sum as i32)
+    }
+
+    /// Adds `delta` to the counter, which starts from 0 if the key is
+    /// missing. Returns the new value.
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.update_counter(key, |x| {
+            x.checked_add(delta)
+                .ok_or_else(|| KvError::OutOfRange(format!("adding {} to {} overflows", delta, x)))
+        })
+    }
+
+    /// Subtracts `delta` from the counter, which starts from 0 if the key is
+    /// missing. Returns the new value.
+    pub fn decr_by(&mut self, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.update_counter(key, |x| {
+            x.checked_sub(delta)
+                .ok_or_else(|| KvError::OutOfRange(format!("subtracting {} from {} overflows", delta, x)))
+        })
+    }
+
+    fn update_counter(&mut self, key: &str, f: impl FnOnce(i64) -> Result<i64, KvError>) -> Result<i64, KvError> {
+        self.modify(key, |slot| {
+            let existed = slot.is_some();
+            let current = match slot {
+                Some(KeyValueItem::Atomic(x)) => *x,
+                Some(v) => return Err(wrong_type(ItemType::Atomic, v)),
+                None => 0,
+            };
+            let value = f(current)?;
+            *slot = Some(KeyValueItem::Atomic(value));
+            Ok((value, !existed || value != current))
+        })
+    }
+
+    /// Adds `delta` to the float counter, which starts from 0 if the key is
+    /// missing. A result that is infinite or NaN fails with `OutOfRange`.
+    pub fn incr_by_float(&mut self, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.modify(key, |slot| {
+            let existed = slot.is_some();
+            let current = match slot {
+                Some(KeyValueItem::Float(x)) => *x,
+                Some(v) => return Err(wrong_type(ItemType::Float, v)),
+                None => 0.0,
+            };
+            let value = current + delta;
+            if !value.is_finite() {
+                return Err(KvError::OutOfRange(format!("adding {} to {} gives {}", delta, current, value)));
+            }
+            *slot = Some(KeyValueItem::Float(value));
+            Ok((value, !existed || value != current))
+        })
+    }
+
+    pub fn del(&mut self, key: &str) -> Result<(), KvError> {
+        self.touch(key)?;
+        if self.items.item_type(key)?.is_some() {
+            self.write_count += 1;
+            self.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// The type of item stored at `key`, `None` if there is none
+    pub fn item_type(&self, key: &str) -> Result<Option<ItemType>, KvError> {
+        if self.is_expired(key, self.now()) {
+            return Ok(None);
+        }
+        Ok(self.items.item_type(key)?)
+    }
+
+    pub fn exists(&self, key: &str) -> Result<bool, KvError> {
+        Ok(self.item_type(key)?.is_some())
+    }
+
+    /// One page of a scan over the keys starting with `prefix`, in byte order.
+    /// Looks at up to `count` keys after `cursor`, or from the first one if it
+    /// is `None`, and keeps the live ones that match `pattern` with the prefix
+    /// cut off and hold an `item_type`. Returns them with the cursor of the
+    /// next page, `None` once every key has been looked at. The cursor is a
+    /// key rather than a position, so a key that exists for the whole scan is
+    /// returned exactly once however the store changes between pages.
+    pub fn scan(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        count: usize,
+        pattern: Option<&str>,
+        item_type: Option<ItemType>,
+    ) -> Result<(Vec<String>, Option<String>), KvError> {
+        let keys = self.items.keys_after(prefix, cursor, count);
+        let next = if keys.len() < count { None } else { keys.last().cloned() };
+        let mut found = Vec::new();
+        for key in keys {
+            if !pattern.map_or(true, |p| glob::matches(p, &key[prefix.len()..])) {
+                continue;
+            }
+            let wanted = match self.item_type(&key)? {
+                Some(actual) => item_type.map_or(true, |ty| actual == ty),
+                None => false,
+            };
+            if wanted {
+                found.push(key);
+            }
+        }
+        Ok((found, next))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Vec<u8>, KvError> {
+        self.live(key)?.map_or_else(
+            || Err(KvError::NoSuchKey),
             |v| {
-                if let KeyValueItem::Set(ref s) = v {
-                    Ok(s.iter().cloned().collect())
+                if let KeyValueItem::Scalar(ref s) = *v {
+                    Ok(s.clone())
                 } else {
-                    Err("attempt to query non-set".into())
+                    Err(wrong_type(ItemType::Scalar, &v))
                 }
             },
         )
     }
-}
 
-fn new_set(value: Vec<u8>) -> KeyValueItem {
-    let mut x = HashSet::new();
-    x.insert(value);
-    KeyValueItem::Set(x)
-}
+    /// The values from `start` to `stop`, both inclusive. Either end counts
+    /// from the tail when negative, -1 being the last value, and is clamped to
+    /// the list. A missing key or a range that ends before it starts gives an
+    /// empty result.
+    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<Vec<u8>>, KvError> {
+        match self.live(key)? {
+            Some(v) => match *v {
+                KeyValueItem::List(ref l) => Ok(list_range(start, stop, l.len())
+                    .map(|range| l.range(range).cloned().collect())
+                    .unwrap_or_default()),
+                _ => Err(wrong_type(ItemType::List, &v)),
+            },
+            None => Ok(Vec::new()),
+        }
+    }
 
-#[cfg(test)]
-mod test {
-    use super::KeyValueStore;
+    /// Inserts the values at the head of the list, one after the other, so the
+    /// last one ends up first. Returns the new length.
+    pub fn lpush(&mut self, key: &str, values: Vec<Vec<u8>>) -> Result<usize, KvError> {
+        self.push(key, ListEnd::Left, values)
+    }
 
-    fn gen_store() -> KeyValueStore {
-        let mut store = KeyValueStore::new();
-        store.sadd("test", "bob".to_owned().into_bytes()).unwrap();
-        store.sadd("test", "alice".to_owned().into_bytes()).unwrap();
-        store.sadd("test", "dave".to_owned().into_bytes()).unwrap();
-        store.sadd("test2", "bob".to_owned().into_bytes()).unwrap();
-        store.sadd("test2", "dave".to_owned().into_bytes()).unwrap();
+    /// Appends the values to the tail of the list. Returns the new length.
+    pub fn rpush(&mut self, key: &str, values: Vec<Vec<u8>>) -> Result<usize, KvError> {
+        self.push(key, ListEnd::Right, values)
+    }
 
-        store.lpush("list1", "first".to_owned().into_bytes()).unwrap();
-        store.lpush("list1", "second".to_owned().into_bytes()).unwrap();
-        store.lpush("list1", "third".to_owned().into_bytes()).unwrap();
+    fn push(&mut self, key: &str, end: ListEnd, values: Vec<Vec<u8>>) -> Result<usize, KvError> {
+        self.modify(key, |slot| {
+            if slot.is_none() && values.is_empty() {
+                return Ok((0, false));
+            }
+            let l = match slot.get_or_insert_with(|| KeyValueItem::List(VecDeque::new())) {
+                KeyValueItem::List(l) => l,
+                v => return Err(wrong_type(ItemType::List, v)),
+            };
+            let changed = !values.is_empty();
+            match end {
+                ListEnd::Left => {
+                    for value in values {
+                        l.push_front(value);
+                    }
+                }
+                ListEnd::Right => l.extend(values),
+            }
+            Ok((l.len(), changed))
+        })
+    }
 
-        store.incr("counter", 5).unwrap();
+    /// Removes and returns up to `count` values from the head of the list
+    pub fn lpop(&mut self, key: &str, count: usize) -> Result<Vec<Vec<u8>>, KvError> {
+        self.pop(key, ListEnd::Left, count)
+    }
 
-        store.set("setkey", "setval".to_owned().into_bytes()).unwrap();
+    /// Removes and returns up to `count` values from the tail of the list, last one first
+    pub fn rpop(&mut self, key: &str, count: usize) -> Result<Vec<Vec<u8>>, KvError> {
+        self.pop(key, ListEnd::Right, count)
+    }
 
-        store
+    fn pop(&mut self, key: &str, end: ListEnd, count: usize) -> Result<Vec<Vec<u8>>, KvError> {
+        self.modify(key, |slot| {
+            let popped = match slot {
+                Some(KeyValueItem::List(l)) => {
+                    let count = count.min(l.len());
+                    match end {
+                        ListEnd::Left => l.drain(..count).collect(),
+                        ListEnd::Right => l.drain(l.len() - count..).rev().collect(),
+                    }
+                }
+                Some(v) => return Err(wrong_type(ItemType::List, v)),
+                None => Vec::new(),
+            };
+            drop_empty_list(slot);
+            let changed = !popped.is_empty();
+            Ok((popped, changed))
+        })
     }
 
-    #[test]
-    fn test_intersect() {
-        let store = gen_store();
+    pub fn llen(&self, key: &str) -> Result<usize, KvError> {
+        match self.live(key)? {
+            Some(v) => match *v {
+                KeyValueItem::List(ref l) => Ok(l.len()),
+                _ => Err(wrong_type(ItemType::List, &v)),
+            },
+            None => Ok(0),
+        }
+    }
 
-        let inter = store
-            .sinter(vec!["test".to_string(), "test2".to_string()])
-            .unwrap();
-        assert!(inter.contains(&"bob".to_owned().into_bytes()));
-        assert!(inter.contains(&"dave".to_owned().into_bytes()));
-        assert_eq!(false, inter.contains(&"alice".to_owned().into_bytes()));
+    /// The value at `index`, counting from the tail if negative. `None` if the
+    /// key is missing or the index lies outside the list.
+    pub fn lindex(&self, key: &str, index: i64) -> Result<Option<Vec<u8>>, KvError> {
+        match self.live(key)? {
+            Some(v) => match *v {
+                KeyValueItem::List(ref l) => Ok(list_index(index, l.len()).map(|i| l[i].clone())),
+                _ => Err(wrong_type(ItemType::List, &v)),
+            },
+            None => Ok(None),
+        }
     }
 
-    #[test]
-    fn test_union() {
-        let store = gen_store();
+    /// Replaces the value at `index`, counting from the tail if negative
+    pub fn lset(&mut self, key: &str, index: i64, value: Vec<u8>) -> Result<(), KvError> {
+        self.modify(key, |slot| match slot {
+            Some(KeyValueItem::List(l)) => match list_index(index, l.len()) {
+                Some(i) => {
+                    let changed = l[i] != value;
+                    l[i] = value;
+                    Ok(((), changed))
+                }
+                None => Err(KvError::OutOfRange(format!("index {} of a list of {}", index, l.len()))),
+            },
+            Some(v) => Err(wrong_type(ItemType::List, v)),
+            None => Err(KvError::NoSuchKey),
+        })
+    }
 
-        let union = store
-            .sunion(vec!["test".to_string(), "test2".to_string()])
-            .unwrap();
-        assert_eq!(3, union.len());
+    /// Inserts `value` before or after the first occurrence of `pivot`. Returns
+    /// the new length, 0 if the key is missing and -1 if `pivot` is not in the list.
+    pub fn linsert(&mut self, key: &str, pivot: &[u8], value: Vec<u8>, before: bool) -> Result<i64, KvError> {
+        self.modify(key, |slot| match slot {
+            Some(KeyValueItem::List(l)) => match l.iter().position(|v| v.as_slice() == pivot) {
+                Some(i) => {
+                    l.insert(if before { i } else { i + 1 }, value);
+                    Ok((l.len() as i64, true))
+                }
+                None => Ok((-1, false)),
+            },
+            Some(v) => Err(wrong_type(ItemType::List, v)),
+            None => Ok((0, false)),
+        })
     }
 
-    #[test]
-    fn test_get_set() {
-        let store = gen_store();
+    /// Keeps only the values from `start` to `stop`, both inclusive and counted
+    /// from the tail if negative. Returns the new length.
+    pub fn ltrim(&mut self, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        self.modify(key, |slot| {
+            let (len, changed) = match slot {
+                Some(KeyValueItem::List(l)) => {
+                    let old_len = l.len();
+                    match list_range(start, stop, l.len()) {
+                        Some(range) => {
+                            l.truncate(range.end);
+                            l.drain(..range.start);
+                        }
+                        None => l.clear(),
+                    }
+                    (l.len(), l.len() != old_len)
+                }
+                Some(v) => return Err(wrong_type(ItemType::List, v)),
+                None => (0, false),
+            };
+            drop_empty_list(slot);
+            Ok((len, changed))
+        })
+    }
 
-        assert_eq!("setval".to_owned().into_bytes(), store.get("setkey").unwrap());
+    /// Pops a value off one end of `source` and pushes it onto one end of
+    /// `destination`. Returns the value moved, `None` if `source` was empty.
+    pub fn lmove(
+        &mut self,
+        source: &str,
+        destination: &str,
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Result<Option<Vec<u8>>, KvError> {
+        // Check the destination first so a value is never popped and then lost
+        if let Some(v) = self.live(destination)? {
+            if v.item_type() != ItemType::List {
+                return Err(wrong_type(ItemType::List, &v));
+            }
+        }
+        let value = match self.pop(source, from, 1)?.pop() {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        self.push(destination, to, vec![value.clone()])?;
+        Ok(Some(value))
     }
 
-    #[test]
-    fn test_list() {
-        let store = gen_store();
-        assert_eq!(
-            vec!["first".to_owned().into_bytes(), "second".to_owned().into_bytes(), "third".to_owned().into_bytes()],
-            store.lrange("list1", 0, 100).unwrap()
-        );
+    pub fn sv_insert<S: Score>(&mut self, key: &str, value: &(S, Vec<u8>), overwrite: bool) -> Result<bool, KvError> {
+        self.modify(key, |slot| {
+            if let Some(kvec) = sv_slot::<S>(slot)? {
+                if kvec.get(value.0).is_some() && !overwrite {
+                    return Ok((false, false));
+                }
+            }
+            sv_slot_or_new::<S>(slot)?.insert(value.0, value.1.clone());
+            Ok((true, true))
+        })
     }
 
-    #[test]
-    fn test_incr() {
-        let mut store = gen_store();
+    pub fn sv_into_vec<S: Score>(&self, key: &str) -> Result<Vec<(S, Vec<u8>)>, KvError> {
+        Ok(self.read_sv(key, |kvec: &SortedVec<S>| kvec.as_slice().to_vec())?.unwrap_or_default())
+    }
 
-        let a = store.incr("counter", 1).unwrap();
-        let b = store.incr("counter", 1).unwrap();
-        let c = store.incr("counter", -3).unwrap();
+    /// Runs `f` on the sorted vec stored at `key`. `None` if the key is
+    /// missing; a sorted vec with another score type is a type error.
+    fn read_sv<S: Score, R>(&self, key: &str, f: impl FnOnce(&SortedVec<S>) -> R) -> Result<Option<R>, KvError> {
+        match self.live(key)? {
+            Some(v) => match S::sorted_vec(&v) {
+                Some(kvec) => Ok(Some(f(kvec))),
+                None => Err(wrong_type(S::ITEM_TYPE, &v)),
+            },
+            None => Ok(None),
+        }
+    }
 
-        assert_eq!(a, 6);
-        assert_eq!(b, 7);
-        assert_eq!(c, 4);
+    /// Entries whose scores lie within both bounds, lowest score first or
+    /// highest first if `reverse`. `offset` entries are skipped in that order
+    /// and at most `limit` are returned.
+    pub fn sv_range_by_score<S: Score>(
+        &self,
+        key: &str,
+        min: Bound<S>,
+        max: Bound<S>,
+        offset: usize,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<(S, Vec<u8>)>, KvError> {
+        Ok(self
+            .read_sv(key, |kvec: &SortedVec<S>| {
+                let entries = &kvec.as_slice()[kvec.score_range(min, max)];
+                window(entries, offset, limit, reverse)
+            })?
+            .unwrap_or_default())
     }
 
-    #[test]
-    fn test_exists_and_del() {
-        let mut store = gen_store();
+    /// Up to `limit` entries starting at rank `offset`, counted from the lowest
+    /// score or from the highest if `reverse`
+    pub fn sv_range_by_rank<S: Score>(
+        &self,
+        key: &str,
+        offset: usize,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<(S, Vec<u8>)>, KvError> {
+        Ok(self
+            .read_sv(key, |kvec: &SortedVec<S>| window(kvec.as_slice(), offset, limit, reverse))?
+            .unwrap_or_default())
+    }
+
+    /// How many entries have scores within both bounds
+    pub fn sv_count<S: Score>(&self, key: &str, min: Bound<S>, max: Bound<S>) -> Result<usize, KvError> {
+        Ok(self.read_sv(key, |kvec: &SortedVec<S>| kvec.score_range(min, max).len())?.unwrap_or(0))
+    }
+
+    pub fn sv_tail_off<S: Score>(&mut self, key: &str, remain: usize) -> Result<usize, KvError> {
+        self.modify(key, |slot| {
+            let (len, changed) = match sv_slot::<S>(slot)? {
+                Some(kvec) => {
+                    let changed = kvec.len() > remain;
+                    if changed {
+                        kvec.drain(remain..kvec.len());
+                    }
+                    (kvec.len(), changed)
+                }
+                None => (0, false),
+            };
+            drop_empty_sv::<S>(slot);
+            Ok((len, changed))
+        })
+    }
+
+    /// Keeps only the `remain` entries with the highest scores and returns
+    /// the new length. The counterpart of `sv_tail_off`.
+    pub fn sv_head_off<S: Score>(&mut self, key: &str, remain: usize) -> Result<usize, KvError> {
+        self.modify(key, |slot| {
+            let (len, changed) = match sv_slot::<S>(slot)? {
+                Some(kvec) => {
+                    let changed = kvec.len() > remain;
+                    if changed {
+                        kvec.drain(0..kvec.len() - remain);
+                    }
+                    (kvec.len(), changed)
+                }
+                None => (0, false),
+            };
+            drop_empty_sv::<S>(slot);
+            Ok((len, changed))
+        })
+    }
+
+    /// Removes and returns up to `count` entries with the lowest scores, lowest first
+    pub fn sv_pop_min<S: Score>(&mut self, key: &str, count: usize) -> Result<Vec<(S, Vec<u8>)>, KvError> {
+        self.modify(key, |slot| {
+            let popped = match sv_slot::<S>(slot)? {
+                Some(kvec) => kvec.drain(0..count.min(kvec.len())),
+                None => Vec::new(),
+            };
+            drop_empty_sv::<S>(slot);
+            let changed = !popped.is_empty();
+            Ok((popped, changed))
+        })
+    }
+
+    /// Removes and returns up to `count` entries with the highest scores, highest first
+    pub fn sv_pop_max<S: Score>(&mut self, key: &str, count: usize) -> Result<Vec<(S, Vec<u8>)>, KvError> {
+        self.modify(key, |slot| {
+            let popped = match sv_slot::<S>(slot)? {
+                Some(kvec) => {
+                    let len = kvec.len();
+                    let mut popped = kvec.drain(len - count.min(len)..len);
+                    popped.reverse();
+                    popped
+                }
+                None => Vec::new(),
+            };
+            drop_empty_sv::<S>(slot);
+            let changed = !popped.is_empty();
+            Ok((popped, changed))
+        })
+    }
+
+    /// Removes the entries whose scores lie within both bounds. Returns how
+    /// many were removed.
+    pub fn sv_remove_range_by_score<S: Score>(
+        &mut self,
+        key: &str,
+        min: Bound<S>,
+        max: Bound<S>,
+    ) -> Result<usize, KvError> {
+        self.modify(key, |slot| {
+            let removed = match sv_slot::<S>(slot)? {
+                Some(kvec) => {
+                    let range = kvec.score_range(min, max);
+                    kvec.drain(range).len()
+                }
+                None => 0,
+            };
+            drop_empty_sv::<S>(slot);
+            Ok((removed, removed > 0))
+        })
+    }
+
+    pub fn sv_remove_item<S: Score>(&mut self, key: &str, value: (S, Vec<u8>)) -> Result<bool, KvError> {
+        self.modify(key, |slot| {
+            let removed = match sv_slot::<S>(slot)? {
+                Some(kvec) => kvec.remove(value.0).is_some(),
+                None => false,
+            };
+            drop_empty_sv::<S>(slot);
+            Ok((true, removed))
+        })
+    }
+
+    /// Gives each member its score, adding the members that are not in the
+    /// sorted set yet. Returns how many were added.
+    pub fn zadd(&mut self, key: &str, members: Vec<(Vec<u8>, i64)>) -> Result<usize, KvError> {
+        self.modify(key, |slot| {
+            let z = match slot.get_or_insert_with(|| KeyValueItem::SortedSet(SortedSet::new())) {
+                KeyValueItem::SortedSet(z) => z,
+                v => return Err(wrong_type(ItemType::SortedSet, v)),
+            };
+            let mut added = 0;
+            let mut changed = false;
+            for (member, score) in members {
+                changed |= z.score(&member) != Some(score);
+                if z.insert(member, score) {
+                    added += 1;
+                }
+            }
+            drop_empty_zset(slot);
+            Ok((added, changed))
+        })
+    }
+
+    /// Adds `increment` to the score of `member`, which starts from 0 if the
+    /// member is new. Returns the new score.
+    pub fn zincrby(&mut self, key: &str, member: Vec<u8>, increment: i64) -> Result<i64, KvError> {
+        self.modify(key, |slot| {
+            let z = match slot.get_or_insert_with(|| KeyValueItem::SortedSet(SortedSet::new())) {
+                KeyValueItem::SortedSet(z) => z,
+                v => return Err(wrong_type(ItemType::SortedSet, v)),
+            };
+            let old = z.score(&member);
+            let current = old.unwrap_or(0);
+            let score = match current.checked_add(increment) {
+                Some(score) => score,
+                None => {
+                    drop_empty_zset(slot);
+                    return Err(KvError::OutOfRange(format!(
+                        "adding {} to a score of {} overflows",
+                        increment, current
+                    )));
+                }
+            };
+            z.insert(member, score);
+            Ok((score, old != Some(score)))
+        })
+    }
+
+    /// Removes the members and returns how many were in the sorted set. A
+    /// sorted set that becomes empty is removed.
+    pub fn zrem(&mut self, key: &str, members: &[Vec<u8>]) -> Result<usize, KvError> {
+        self.modify(key, |slot| {
+            let removed = match slot {
+                Some(KeyValueItem::SortedSet(z)) => members.iter().filter(|m| z.remove(m)).count(),
+                Some(v) => return Err(wrong_type(ItemType::SortedSet, v)),
+                None => 0,
+            };
+            drop_empty_zset(slot);
+            Ok((removed, removed > 0))
+        })
+    }
+
+    /// Runs `f` on the sorted set stored at `key`. `None` if the key is missing.
+    fn read_zset<R>(&self, key: &str, f: impl FnOnce(&SortedSet) -> R) -> Result<Option<R>, KvError> {
+        match self.live(key)? {
+            Some(v) => match *v {
+                KeyValueItem::SortedSet(ref z) => Ok(Some(f(z))),
+                _ => Err(wrong_type(ItemType::SortedSet, &v)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn zscore(&self, key: &str, member: &[u8]) -> Result<Option<i64>, KvError> {
+        Ok(self.read_zset(key, |z| z.score(member))?.flatten())
+    }
+
+    /// The position of `member` counted from the lowest score, 0 being the first
+    pub fn zrank(&self, key: &str, member: &[u8]) -> Result<Option<usize>, KvError> {
+        Ok(self.read_zset(key, |z| z.rank(member))?.flatten())
+    }
+
+    /// The position of `member` counted from the highest score, 0 being the first
+    pub fn zrevrank(&self, key: &str, member: &[u8]) -> Result<Option<usize>, KvError> {
+        Ok(self
+            .read_zset(key, |z| z.rank(member).map(|rank| z.len() - 1 - rank))?
+            .flatten())
+    }
+
+    pub fn zcard(&self, key: &str) -> Result<usize, KvError> {
+        Ok(self.read_zset(key, |z| z.len())?.unwrap_or(0))
+    }
+
+    /// Members and their scores from rank `start` to `stop`, both inclusive and
+    /// counted from the highest score if negative, lowest score first
+    pub fn zrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<(Vec<u8>, i64)>, KvError> {
+        Ok(self
+            .read_zset(key, |z| match list_range(start, stop, z.len()) {
+                Some(range) => z
+                    .iter()
+                    .skip(range.start)
+                    .take(range.len())
+                    .map(|(member, score)| (member.clone(), score))
+                    .collect(),
+                None => Vec::new(),
+            })?
+            .unwrap_or_default())
+    }
+
+    /// Sets the fields of the hash, adding the ones it does not have yet and
+    /// leaving any other fields alone. Returns how many fields were added.
+    pub fn hset(&mut self, key: &str, fields: Vec<(String, Vec<u8>)>) -> Result<usize, KvError> {
+        self.modify(key, |slot| {
+            let h = hash_slot_or_new(slot)?;
+            let mut added = 0;
+            let mut changed = false;
+            for (field, value) in fields {
+                changed |= h.get(&field) != Some(&value);
+                if h.insert(field, value).is_none() {
+                    added += 1;
+                }
+            }
+            drop_empty_hash(slot);
+            Ok((added, changed))
+        })
+    }
+
+    /// Sets the field only if the hash does not have it yet. Returns true if it was set.
+    pub fn hsetnx(&mut self, key: &str, field: String, value: Vec<u8>) -> Result<bool, KvError> {
+        self.modify(key, |slot| {
+            if let Some(KeyValueItem::Hash(h)) = slot {
+                if h.contains_key(&field) {
+                    return Ok((false, false));
+                }
+            }
+            hash_slot_or_new(slot)?.insert(field, value);
+            Ok((true, true))
+        })
+    }
+
+    /// Removes the fields and returns how many the hash had. A hash that
+    /// becomes empty is removed.
+    pub fn hdel(&mut self, key: &str, fields: &[String]) -> Result<usize, KvError> {
+        self.modify(key, |slot| {
+            let removed = match slot {
+                Some(KeyValueItem::Hash(h)) => fields.iter().filter(|f| h.remove(*f).is_some()).count(),
+                Some(v) => return Err(wrong_type(ItemType::Hash, v)),
+                None => 0,
+            };
+            drop_empty_hash(slot);
+            Ok((removed, removed > 0))
+        })
+    }
+
+    /// Adds `increment` to the field, which is stored as a decimal integer and
+    /// starts from 0 if the hash does not have it. Returns the new value.
+    pub fn hincrby(&mut self, key: &str, field: String, increment: i64) -> Result<i64, KvError> {
+        self.modify(key, |slot| {
+            let h = hash_slot_or_new(slot)?;
+            let current = match h.get(&field) {
+                Some(value) => std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|v| v.parse::<i64>().ok())
+                    .ok_or_else(|| KvError::OutOfRange(format!("field {} does not hold an integer", field)))?,
+                None => 0,
+            };
+            let value = current.checked_add(increment).ok_or_else(|| {
+                KvError::OutOfRange(format!("adding {} to a value of {} overflows", increment, current))
+            })?;
+            let bytes = value.to_string().into_bytes();
+            let changed = h.get(&field) != Some(&bytes);
+            h.insert(field, bytes);
+            Ok((value, changed))
+        })
+    }
+
+    /// Runs `f` on the hash stored at `key`. `None` if the key is missing.
+    fn read_hash<R>(&self, key: &str, f: impl FnOnce(&BTreeMap<String, Vec<u8>>) -> R) -> Result<Option<R>, KvError> {
+        match self.live(key)? {
+            Some(v) => match *v {
+                KeyValueItem::Hash(ref h) => Ok(Some(f(h))),
+                _ => Err(wrong_type(ItemType::Hash, &v)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<Vec<u8>>, KvError> {
+        Ok(self.read_hash(key, |h| h.get(field).cloned())?.flatten())
+    }
+
+    /// The value of each of `fields`, in the same order
+    pub fn hmget(&self, key: &str, fields: &[String]) -> Result<Vec<Option<Vec<u8>>>, KvError> {
+        Ok(self
+            .read_hash(key, |h| fields.iter().map(|f| h.get(f).cloned()).collect())?
+            .unwrap_or_else(|| vec![None; fields.len()]))
+    }
+
+    pub fn hexists(&self, key: &str, field: &str) -> Result<bool, KvError> {
+        Ok(self.read_hash(key, |h| h.contains_key(field))?.unwrap_or(false))
+    }
+
+    pub fn hlen(&self, key: &str) -> Result<usize, KvError> {
+        Ok(self.read_hash(key, |h| h.len())?.unwrap_or(0))
+    }
+
+    /// Field names in byte order
+    pub fn hkeys(&self, key: &str) -> Result<Vec<String>, KvError> {
+        Ok(self.read_hash(key, |h| h.keys().cloned().collect())?.unwrap_or_default())
+    }
+
+    /// Values in the byte order of their field names
+    pub fn hvals(&self, key: &str) -> Result<Vec<Vec<u8>>, KvError> {
+        Ok(self.read_hash(key, |h| h.values().cloned().collect())?.unwrap_or_default())
+    }
+
+    /// Fields and their values in the byte order of the field names
+    pub fn hgetall(&self, key: &str) -> Result<Vec<(String, Vec<u8>)>, KvError> {
+        Ok(self
+            .read_hash(key, |h| h.iter().map(|(f, v)| (f.clone(), v.clone())).collect())?
+            .unwrap_or_default())
+    }
+
+    /// Writing a scalar replaces whatever the key held before, whatever its
+    /// type, and clears any expiry the key had
+    pub fn set(&mut self, key: &str, value: Vec<u8>) -> Result<(), KvError> {
+        self.clear_expiry(key)?;
+        self.modify(key, |slot| {
+            *slot = Some(KeyValueItem::Scalar(value));
+            Ok(((), true))
+        })
+    }
+
+    /// The scalar stored at each of `keys`, in the same order, `None` for
+    /// missing keys. Fails if any of the keys holds another type.
+    pub fn mget(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, KvError> {
+        keys.iter()
+            .map(|key| match self.get(key) {
+                Ok(value) => Ok(Some(value)),
+                Err(KvError::NoSuchKey) => Ok(None),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    /// Writes each scalar like `set`. Returns whether each key existed before.
+    pub fn mset(&mut self, entries: Vec<(String, Vec<u8>)>) -> Result<Vec<bool>, KvError> {
+        entries
+            .into_iter()
+            .map(|(key, value)| {
+                let existed = self.exists(&key)?;
+                self.set(&key, value)?;
+                Ok(existed)
+            })
+            .collect()
+    }
+
+    /// Writes all the scalars like `set` if none of the keys exists, and
+    /// nothing otherwise. Returns whether each key existed.
+    pub fn msetnx(&mut self, entries: Vec<(String, Vec<u8>)>) -> Result<Vec<bool>, KvError> {
+        let existed = entries
+            .iter()
+            .map(|(key, _)| self.exists(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !existed.contains(&true) {
+            self.mset(entries)?;
+        }
+        Ok(existed)
+    }
+
+    /// Deletes the keys and returns whether each of them existed
+    pub fn mdel(&mut self, keys: &[String]) -> Result<Vec<bool>, KvError> {
+        keys.iter()
+            .map(|key| {
+                let existed = self.exists(key)?;
+                self.del(key)?;
+                Ok(existed)
+            })
+            .collect()
+    }
+
+    /// Writes the scalar like `set`, but only if `condition` holds for the key
+    /// as it is now. Returns true if it was written.
+    pub fn set_if(&mut self, key: &str, value: Vec<u8>, condition: SetCondition) -> Result<bool, KvError> {
+        let exists = self.exists(key)?;
+        let write = match condition {
+            SetCondition::Always => true,
+            SetCondition::IfAbsent => !exists,
+            SetCondition::IfPresent => exists,
+        };
+        if write {
+            self.set(key, value)?;
+        }
+        Ok(write)
+    }
+
+    /// Writes the scalar like `set` and returns the scalar it replaced, if
+    /// any. Fails without writing if the key holds another type.
+    pub fn getset(&mut self, key: &str, value: Vec<u8>) -> Result<Option<Vec<u8>>, KvError> {
+        let old = match self.get(key) {
+            Ok(old) => Some(old),
+            Err(KvError::NoSuchKey) => None,
+            Err(e) => return Err(e),
+        };
+        self.set(key, value)?;
+        Ok(old)
+    }
+
+    /// Replaces the scalar with `value` only if it equals `expected` byte for
+    /// byte. Unlike `set` this keeps the expiry of the key. Returns true if
+    /// the scalar was replaced; a missing key never is.
+    pub fn compare_and_swap(&mut self, key: &str, expected: &[u8], value: Vec<u8>) -> Result<bool, KvError> {
+        self.modify(key, |slot| match slot {
+            Some(KeyValueItem::Scalar(s)) if s.as_slice() == expected => {
+                *s = value;
+                Ok((true, true))
+            }
+            Some(KeyValueItem::Scalar(_)) | None => Ok((false, false)),
+            Some(v) => Err(wrong_type(ItemType::Scalar, v)),
+        })
+    }
+
+    pub fn set_ex(&mut self, key: &str, value: Vec<u8>, ttl_ms: u64) -> Result<(), KvError> {
+        self.set(key, value)?;
+        self.expire(key, ttl_ms)?;
+        Ok(())
+    }
+
+    pub fn lrem(&mut self, key: &str, value: Vec<u8>) -> Result<i32, KvError> {
+        self.modify(key, |slot| match slot {
+            Some(KeyValueItem::List(l)) => {
+                let old_len = l.len();
+                l.retain(|i| *i != value);
+                let len = l.len();
+                drop_empty_list(slot);
+                Ok((len as _, len != old_len))
+            }
+            Some(v) => Err(wrong_type(ItemType::List, v)),
+            None => Ok((0, false)),
+        })
+    }
+
+    pub fn sadd(&mut self, key: &str, value: Vec<u8>) -> Result<i32, KvError> {
+        self.modify(key, |slot| match slot {
+            Some(KeyValueItem::Set(s)) => {
+                let added = s.insert(value);
+                Ok((s.len() as _, added))
+            }
+            Some(v) => Err(wrong_type(ItemType::Set, v)),
+            None => {
+                *slot = Some(new_set(value));
+                Ok((1, true))
+            }
+        })
+    }
+
+    pub fn srem(&mut self, key: &str, value: Vec<u8>) -> Result<i32, KvError> {
+        self.modify(key, |slot| match slot {
+            Some(KeyValueItem::Set(s)) => {
+                let removed = s.remove(&value);
+                let len = s.len();
+                drop_empty_set(slot);
+                Ok((len as _, removed))
+            }
+            Some(v) => Err(wrong_type(ItemType::Set, v)),
+            None => Ok((0, false)),
+        })
+    }
+
+    /// Removes several members at once and returns the new cardinality
+    pub fn srem_members(&mut self, key: &str, members: &[Vec<u8>]) -> Result<usize, KvError> {
+        self.modify(key, |slot| match slot {
+            Some(KeyValueItem::Set(s)) => {
+                let mut removed = false;
+                for member in members {
+                    removed |= s.remove(member);
+                }
+                let len = s.len();
+                drop_empty_set(slot);
+                Ok((len, removed))
+            }
+            Some(v) => Err(wrong_type(ItemType::Set, v)),
+            None => Ok((0, false)),
+        })
+    }
+
+    /// Runs `f` on the set stored at `key`. `None` if the key is missing.
+    fn read_set<R>(&self, key: &str, f: impl FnOnce(&BTreeSet<Vec<u8>>) -> R) -> Result<Option<R>, KvError> {
+        match self.live(key)? {
+            Some(v) => match *v {
+                KeyValueItem::Set(ref s) => Ok(Some(f(s))),
+                _ => Err(wrong_type(ItemType::Set, &v)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn sismember(&self, key: &str, member: &[u8]) -> Result<bool, KvError> {
+        Ok(self.read_set(key, |s| s.contains(member))?.unwrap_or(false))
+    }
+
+    /// Membership of each of `members`, in the same order
+    pub fn smismember(&self, key: &str, members: &[Vec<u8>]) -> Result<Vec<bool>, KvError> {
+        Ok(self
+            .read_set(key, |s| members.iter().map(|m| s.contains(m)).collect())?
+            .unwrap_or_else(|| vec![false; members.len()]))
+    }
+
+    pub fn scard(&self, key: &str) -> Result<usize, KvError> {
+        Ok(self.read_set(key, |s| s.len())?.unwrap_or(0))
+    }
+
+    /// Members of the first set that are in none of the others. Missing keys
+    /// count as empty sets.
+    pub fn sdiff(&self, keys: &[String]) -> Result<Vec<Vec<u8>>, KvError> {
+        let sets = keys
+            .iter()
+            .map(|key| self.lookup_set(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (first, rest) = match sets.split_first() {
+            Some(split) => split,
+            None => return Ok(Vec::new()),
+        };
+        Ok(first
+            .iter()
+            .filter(|m| !rest.iter().any(|s| s.contains(*m)))
+            .cloned()
+            .collect())
+    }
+
+    /// Up to `count` distinct members picked at random
+    pub fn srandmember(&self, key: &str, count: usize) -> Result<Vec<Vec<u8>>, KvError> {
+        Ok(self
+            .read_set(key, |s| {
+                let picks = self.pick(s.len(), count);
+                pick_members(s, &picks)
+            })?
+            .unwrap_or_default())
+    }
+
+    /// Removes and returns up to `count` members picked at random
+    pub fn spop(&mut self, key: &str, count: usize) -> Result<Vec<Vec<u8>>, KvError> {
+        let picks = self.pick(self.scard(key)?, count);
+        self.modify(key, |slot| match slot {
+            Some(KeyValueItem::Set(s)) => {
+                let members = pick_members(s, &picks);
+                for member in members.iter() {
+                    s.remove(member);
+                }
+                drop_empty_set(slot);
+                let changed = !members.is_empty();
+                Ok((members, changed))
+            }
+            Some(v) => Err(wrong_type(ItemType::Set, v)),
+            None => Ok((Vec::new(), false)),
+        })
+    }
+
+    /// Sorted positions of up to `count` distinct members of a set of `len`
+    fn pick(&self, len: usize, count: usize) -> Vec<usize> {
+        let mut rng = self.rng.lock().unwrap();
+        let mut picks = index::sample(&mut **rng, len, count.min(len)).into_vec();
+        picks.sort();
+        picks
+    }
+
+    /// Moves `member` from `source` to `destination`. Returns false if it was
+    /// not in `source`.
+    pub fn smove(&mut self, source: &str, destination: &str, member: Vec<u8>) -> Result<bool, KvError> {
+        // Check the destination first so a member is never removed and then lost
+        if let Some(v) = self.live(destination)? {
+            if v.item_type() != ItemType::Set {
+                return Err(wrong_type(ItemType::Set, &v));
+            }
+        }
+        let removed = self.modify(source, |slot| match slot {
+            Some(KeyValueItem::Set(s)) => {
+                let removed = s.remove(&member);
+                drop_empty_set(slot);
+                Ok((removed, removed))
+            }
+            Some(v) => Err(wrong_type(ItemType::Set, v)),
+            None => Ok((false, false)),
+        })?;
+        if removed {
+            self.sadd(destination, member)?;
+        }
+        Ok(removed)
+    }
+
+    pub fn sunionstore(&mut self, destination: &str, keys: Vec<String>) -> Result<usize, KvError> {
+        let members = self.sunion(keys)?;
+        self.store_set(destination, members)
+    }
+
+    pub fn sinterstore(&mut self, destination: &str, keys: Vec<String>) -> Result<usize, KvError> {
+        let members = self.sinter(keys)?;
+        self.store_set(destination, members)
+    }
+
+    pub fn sdiffstore(&mut self, destination: &str, keys: &[String]) -> Result<usize, KvError> {
+        let members = self.sdiff(keys)?;
+        self.store_set(destination, members)
+    }
+
+    /// Replaces whatever `destination` held, and its expiry, with a set of
+    /// `members`. An empty result removes the key. Returns the cardinality.
+    fn store_set(&mut self, destination: &str, members: Vec<Vec<u8>>) -> Result<usize, KvError> {
+        self.clear_expiry(destination)?;
+        let set: BTreeSet<Vec<u8>> = members.into_iter().collect();
+        let len = set.len();
+        self.modify(destination, |slot| {
+            *slot = if set.is_empty() { None } else { Some(KeyValueItem::Set(set)) };
+            Ok((len, true))
+        })
+    }
+
+    /// The set stored at `key`, borrowed if the backend allows it, or an empty
+    /// set if the key is missing
+    fn lookup_set(&self, key: &str) -> Result<Cow<'_, BTreeSet<Vec<u8>>>, KvError> {
+        match self.live(key)? {
+            Some(Cow::Borrowed(KeyValueItem::Set(s))) => Ok(Cow::Borrowed(s)),
+            Some(Cow::Owned(KeyValueItem::Set(s))) => Ok(Cow::Owned(s)),
+            Some(v) => Err(wrong_type(ItemType::Set, &v)),
+            None => Ok(Cow::Owned(BTreeSet::new())),
+        }
+    }
+
+    /// Members that are in any of the sets. Missing keys count as empty sets.
+    pub fn sunion(&self, keys: Vec<String>) -> Result<Vec<Vec<u8>>, KvError> {
+        let sets = keys
+            .iter()
+            .map(|key| self.lookup_set(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let union: HashSet<&Vec<u8>> = sets.iter().flat_map(|s| s.iter()).collect();
+        Ok(union.into_iter().cloned().collect())
+    }
+
+    /// Members that are in every one of the sets. Missing keys count as empty
+    /// sets, so any missing key makes the result empty, as does an empty `keys`.
+    /// Only the smallest set is walked; the others are probed.
+    pub fn sinter(&self, keys: Vec<String>) -> Result<Vec<Vec<u8>>, KvError> {
+        let mut sets = keys
+            .iter()
+            .map(|key| self.lookup_set(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        sets.sort_by_key(|s| s.len());
+        let (smallest, rest) = match sets.split_first() {
+            Some(split) => split,
+            None => return Ok(Vec::new()),
+        };
+        Ok(smallest
+            .iter()
+            .filter(|m| rest.iter().all(|s| s.contains(*m)))
+            .cloned()
+            .collect())
+    }
+
+    pub fn smembers(&self, key: String) -> Result<Vec<Vec<u8>>, KvError> {
+        self.live(&key)?.map_or_else(
+            || Ok(vec![]),
+            |v| {
+                if let KeyValueItem::Set(ref s) = *v {
+                    Ok(s.iter().cloned().collect())
+                } else {
+                    Err(wrong_type(ItemType::Set, &v))
+                }
+            },
+        )
+    }
+}
+
+/// Resolves an index counted from the tail if negative. `None` if it lies
+/// outside a list of `len` values.
+fn list_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { index + len as i64 } else { index };
+    if index >= 0 && (index as usize) < len {
+        Some(index as usize)
+    } else {
+        None
+    }
+}
+
+/// Resolves an inclusive range whose ends are counted from the tail if
+/// negative, clamped to a list of `len` values. `None` if nothing is left.
+fn list_range(start: i64, stop: i64, len: usize) -> Option<Range<usize>> {
+    let len = len as i64;
+    let start = if start < 0 { (start + len).max(0) } else { start };
+    let stop = if stop < 0 { stop + len } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        None
+    } else {
+        Some(start as usize..stop as usize + 1)
+    }
+}
+
+/// The sorted vec in `slot`, `None` if the slot is empty
+fn sv_slot<S: Score>(slot: &mut Option<KeyValueItem>) -> Result<Option<&mut SortedVec<S>>, KvError> {
+    match slot {
+        Some(item) => {
+            let actual = item.item_type();
+            match S::sorted_vec_mut(item) {
+                Some(kvec) => Ok(Some(kvec)),
+                None => Err(KvError::WrongType {
+                    expected: S::ITEM_TYPE,
+                    actual,
+                }),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Sorted vecs without entries are removed like other empty collections, so
+/// a drained key also stops being held to its score type
+fn drop_empty_sv<S: Score>(slot: &mut Option<KeyValueItem>) {
+    if let Some(kvec) = slot.as_ref().and_then(S::sorted_vec) {
+        if kvec.is_empty() {
+            *slot = None;
+        }
+    }
+}
+
+/// The sorted vec in `slot`, putting an empty one there if the slot is empty
+fn sv_slot_or_new<S: Score>(slot: &mut Option<KeyValueItem>) -> Result<&mut SortedVec<S>, KvError> {
+    let item = slot.get_or_insert_with(|| S::into_item(SortedVec::new()));
+    let actual = item.item_type();
+    S::sorted_vec_mut(item).ok_or(KvError::WrongType {
+        expected: S::ITEM_TYPE,
+        actual,
+    })
+}
+
+/// Up to `limit` entries after skipping `offset`, walking from the end if `reverse`
+fn window<S: Clone>(
+    entries: &[(S, Vec<u8>)],
+    offset: usize,
+    limit: Option<usize>,
+    reverse: bool,
+) -> Vec<(S, Vec<u8>)> {
+    let rest = entries.len() - offset.min(entries.len());
+    let take = limit.map_or(rest, |limit| limit.min(rest));
+    if reverse {
+        entries[rest - take..rest].iter().rev().cloned().collect()
+    } else {
+        let start = entries.len() - rest;
+        entries[start..start + take].to_vec()
+    }
+}
+
+/// The hash at `slot`, created empty if the key is missing
+fn hash_slot_or_new(slot: &mut Option<KeyValueItem>) -> Result<&mut BTreeMap<String, Vec<u8>>, KvError> {
+    match slot.get_or_insert_with(|| KeyValueItem::Hash(BTreeMap::new())) {
+        KeyValueItem::Hash(h) => Ok(h),
+        v => Err(wrong_type(ItemType::Hash, v)),
+    }
+}
+
+/// Hashes without fields are removed, so an empty hash and a missing key look the same
+fn drop_empty_hash(slot: &mut Option<KeyValueItem>) {
+    if let Some(KeyValueItem::Hash(h)) = slot {
+        if h.is_empty() {
+            *slot = None;
+        }
+    }
+}
+
+/// Sets that become empty are removed, like lists
+fn drop_empty_set(slot: &mut Option<KeyValueItem>) {
+    if let Some(KeyValueItem::Set(s)) = slot {
+        if s.is_empty() {
+            *slot = None;
+        }
+    }
+}
+
+/// Sorted sets that become empty are removed, like lists
+fn drop_empty_zset(slot: &mut Option<KeyValueItem>) {
+    if let Some(KeyValueItem::SortedSet(z)) = slot {
+        if z.is_empty() {
+            *slot = None;
+        }
+    }
+}
+
+/// Lists that become empty are removed, so an empty list and a missing key look the same
+fn drop_empty_list(slot: &mut Option<KeyValueItem>) {
+    if let Some(KeyValueItem::List(l)) = slot {
+        if l.is_empty() {
+            *slot = None;
+        }
+    }
+}
+
+/// The members at the given sorted positions of the set, found in a single
+/// walk that stops at the last of them
+fn pick_members(set: &BTreeSet<Vec<u8>>, picks: &[usize]) -> Vec<Vec<u8>> {
+    let mut members = set.iter();
+    let mut next = 0;
+    picks
+        .iter()
+        .filter_map(|&pick| {
+            let member = members.nth(pick - next);
+            next = pick + 1;
+            member.cloned()
+        })
+        .collect()
+}
+
+fn new_set(value: Vec<u8>) -> KeyValueItem {
+    let mut x = BTreeSet::new();
+    x.insert(value);
+    KeyValueItem::Set(x)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{now_millis, ItemType, KeyValueItem, KeyValueStore, TTL_NO_EXPIRY, TTL_NO_KEY};
+    use crate::error::KvError;
+    use crate::protocol::{ListEnd, SetCondition};
+    use crate::sorted_vec::F64;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    fn gen_store() -> KeyValueStore {
+        let mut store = KeyValueStore::new();
+        store.sadd("test", "bob".to_owned().into_bytes()).unwrap();
+        store.sadd("test", "alice".to_owned().into_bytes()).unwrap();
+        store.sadd("test", "dave".to_owned().into_bytes()).unwrap();
+        store.sadd("test2", "bob".to_owned().into_bytes()).unwrap();
+        store.sadd("test2", "dave".to_owned().into_bytes()).unwrap();
+
+        store.rpush("list1", vec!["first".to_owned().into_bytes()]).unwrap();
+        store.rpush("list1", vec!["second".to_owned().into_bytes()]).unwrap();
+        store.rpush("list1", vec!["third".to_owned().into_bytes()]).unwrap();
+
+        store.incr("counter", 5).unwrap();
+
+        store.set("setkey", "setval".to_owned().into_bytes()).unwrap();
+
+        store
+    }
+
+    #[test]
+    fn test_intersect() {
+        let store = gen_store();
+
+        let inter = store
+            .sinter(vec!["test".to_string(), "test2".to_string()])
+            .unwrap();
+        assert!(inter.contains(&"bob".to_owned().into_bytes()));
+        assert!(inter.contains(&"dave".to_owned().into_bytes()));
+        assert_eq!(false, inter.contains(&"alice".to_owned().into_bytes()));
+    }
+
+    #[test]
+    fn test_intersect_missing_keys() {
+        let store = gen_store();
+
+        assert!(store.sinter(vec![]).unwrap().is_empty());
+        assert!(store.sinter(vec!["missing".to_string()]).unwrap().is_empty());
+        assert!(store
+            .sinter(vec!["test".to_string(), "missing".to_string()])
+            .unwrap()
+            .is_empty());
+        assert!(store.sunion(vec![]).unwrap().is_empty());
+        assert_eq!(3, store.sunion(vec!["missing".to_string(), "test".to_string()]).unwrap().len());
+        assert!(store.sinter(vec!["test".to_string(), "setkey".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_union() {
+        let store = gen_store();
+
+        let union = store
+            .sunion(vec!["test".to_string(), "test2".to_string()])
+            .unwrap();
+        assert_eq!(3, union.len());
+    }
+
+    #[test]
+    fn test_get_set() {
+        let store = gen_store();
+
+        assert_eq!("setval".to_owned().into_bytes(), store.get("setkey").unwrap());
+    }
+
+    #[test]
+    fn test_list() {
+        let store = gen_store();
+        assert_eq!(
+            vec!["first".to_owned().into_bytes(), "second".to_owned().into_bytes(), "third".to_owned().into_bytes()],
+            store.lrange("list1", 0, 100).unwrap()
+        );
+        assert_eq!(
+            vec!["first".to_owned().into_bytes(), "second".to_owned().into_bytes()],
+            store.lrange("list1", 0, 1).unwrap()
+        );
+        assert_eq!(
+            vec!["second".to_owned().into_bytes(), "third".to_owned().into_bytes()],
+            store.lrange("list1", -2, -1).unwrap()
+        );
+        assert_eq!(3, store.lrange("list1", -100, 2).unwrap().len());
+        assert!(store.lrange("list1", 2, 1).unwrap().is_empty());
+        assert!(store.lrange("list1", 5, 10).unwrap().is_empty());
+        assert!(store.lrange("list1", -1, -3).unwrap().is_empty());
+        assert!(store.lrange("missing", 0, -1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let store = gen_store();
+
+        assert_eq!(KvError::NoSuchKey, store.get("missing").unwrap_err());
+        assert_eq!(
+            KvError::WrongType {
+                expected: ItemType::Scalar,
+                actual: ItemType::List
+            },
+            store.get("list1").unwrap_err()
+        );
+        assert_eq!(
+            KvError::WrongType {
+                expected: ItemType::Set,
+                actual: ItemType::Scalar
+            },
+            store.smembers("setkey".to_string()).unwrap_err()
+        );
+    }
+
+    fn holding(ty: ItemType) -> KeyValueStore {
+        let mut store = KeyValueStore::new();
+        match ty {
+            ItemType::Atomic => store.incr("k", 1).map(|_| ()),
+            ItemType::Float => store.incr_by_float("k", 1.0).map(|_| ()),
+            ItemType::Scalar => store.set("k", b"v".to_vec()),
+            ItemType::List => store.rpush("k", vec![b"v".to_vec()]).map(|_| ()),
+            ItemType::Set => store.sadd("k", b"v".to_vec()).map(|_| ()),
+            ItemType::SortedVec => store.sv_insert("k", &(1, b"v".to_vec()), false).map(|_| ()),
+            ItemType::SortedVecI64 => store.sv_insert("k", &(1i64, b"v".to_vec()), false).map(|_| ()),
+            ItemType::SortedVecU64 => store.sv_insert("k", &(1u64, b"v".to_vec()), false).map(|_| ()),
+            ItemType::SortedVecF64 => store.sv_insert("k", &(F64(1.0), b"v".to_vec()), false).map(|_| ()),
+            ItemType::SortedSet => store.zadd("k", vec![(b"v".to_vec(), 1)]).map(|_| ()),
+            ItemType::Hash => store.hset("k", vec![("f".to_string(), b"v".to_vec())]).map(|_| ()),
+        }
+        .unwrap();
+        store
+    }
+
+    fn type_of(store: &KeyValueStore, key: &str) -> Option<ItemType> {
+        store.live(key).unwrap().map(|v| v.item_type())
+    }
+
+    #[test]
+    fn test_mutators_check_type() {
+        type Mutator = fn(&mut KeyValueStore) -> Result<(), KvError>;
+        let mutators: Vec<(&str, ItemType, Mutator)> = vec![
+            ("incr", ItemType::Atomic, |s| s.incr("k", 1).map(|_| ())),
+            ("incr_by", ItemType::Atomic, |s| s.incr_by("k", 1).map(|_| ())),
+            ("decr_by", ItemType::Atomic, |s| s.decr_by("k", 1).map(|_| ())),
+            ("incr_by_float", ItemType::Float, |s| s.incr_by_float("k", 1.0).map(|_| ())),
+            ("lpush", ItemType::List, |s| s.lpush("k", vec![b"x".to_vec()]).map(|_| ())),
+            ("rpush", ItemType::List, |s| s.rpush("k", vec![b"x".to_vec()]).map(|_| ())),
+            ("lpop", ItemType::List, |s| s.lpop("k", 0).map(|_| ())),
+            ("rpop", ItemType::List, |s| s.rpop("k", 0).map(|_| ())),
+            ("lset", ItemType::List, |s| s.lset("k", 0, b"x".to_vec())),
+            ("linsert", ItemType::List, |s| s.linsert("k", b"v", b"x".to_vec(), true).map(|_| ())),
+            ("ltrim", ItemType::List, |s| s.ltrim("k", 0, -1).map(|_| ())),
+            ("lmove", ItemType::List, |s| s.lmove("k", "k", ListEnd::Left, ListEnd::Right).map(|_| ())),
+            ("lrem", ItemType::List, |s| s.lrem("k", b"x".to_vec()).map(|_| ())),
+            ("sadd", ItemType::Set, |s| s.sadd("k", b"x".to_vec()).map(|_| ())),
+            ("srem", ItemType::Set, |s| s.srem("k", b"x".to_vec()).map(|_| ())),
+            ("spop", ItemType::Set, |s| s.spop("k", 0).map(|_| ())),
+            ("smove", ItemType::Set, |s| s.smove("k", "k", b"v".to_vec()).map(|_| ())),
+            ("srem_members", ItemType::Set, |s| s.srem_members("k", &[b"x".to_vec()]).map(|_| ())),
+            ("sv_insert", ItemType::SortedVec, |s| s.sv_insert("k", &(2, b"x".to_vec()), true).map(|_| ())),
+            ("sv_tail_off", ItemType::SortedVec, |s| s.sv_tail_off::<i32>("k", 5).map(|_| ())),
+            ("sv_remove_item", ItemType::SortedVec, |s| s.sv_remove_item("k", (2, b"x".to_vec())).map(|_| ())),
+            ("sv_head_off", ItemType::SortedVec, |s| s.sv_head_off::<i32>("k", 5).map(|_| ())),
+            ("sv_pop_min", ItemType::SortedVec, |s| s.sv_pop_min::<i32>("k", 0).map(|_| ())),
+            ("sv_pop_max", ItemType::SortedVec, |s| s.sv_pop_max::<i32>("k", 0).map(|_| ())),
+            ("sv_insert i64", ItemType::SortedVecI64, |s| s.sv_insert("k", &(2i64, b"x".to_vec()), true).map(|_| ())),
+            ("sv_pop_min u64", ItemType::SortedVecU64, |s| s.sv_pop_min::<u64>("k", 0).map(|_| ())),
+            ("sv_tail_off f64", ItemType::SortedVecF64, |s| s.sv_tail_off::<F64>("k", 1).map(|_| ())),
+            ("sv_remove_range_by_score", ItemType::SortedVec, |s| {
+                s.sv_remove_range_by_score("k", Included(5), Unbounded).map(|_| ())
+            }),
+            ("zadd", ItemType::SortedSet, |s| s.zadd("k", vec![(b"x".to_vec(), 2)]).map(|_| ())),
+            ("zincrby", ItemType::SortedSet, |s| s.zincrby("k", b"x".to_vec(), 2).map(|_| ())),
+            ("zrem", ItemType::SortedSet, |s| s.zrem("k", &[b"x".to_vec()]).map(|_| ())),
+            ("hset", ItemType::Hash, |s| s.hset("k", vec![("x".to_string(), b"x".to_vec())]).map(|_| ())),
+            ("hsetnx", ItemType::Hash, |s| s.hsetnx("k", "x".to_string(), b"x".to_vec()).map(|_| ())),
+            ("hdel", ItemType::Hash, |s| s.hdel("k", &["x".to_string()]).map(|_| ())),
+            ("hincrby", ItemType::Hash, |s| s.hincrby("k", "x".to_string(), 1).map(|_| ())),
+        ];
+        let types = [
+            ItemType::Atomic,
+            ItemType::Float,
+            ItemType::Scalar,
+            ItemType::List,
+            ItemType::Set,
+            ItemType::SortedVec,
+            ItemType::SortedVecI64,
+            ItemType::SortedVecU64,
+            ItemType::SortedVecF64,
+            ItemType::SortedSet,
+            ItemType::Hash,
+        ];
+        for (name, expected, mutator) in mutators.iter() {
+            for actual in types.iter() {
+                let mut store = holding(*actual);
+                let result = mutator(&mut store);
+                if actual == expected {
+                    assert_eq!(Ok(()), result, "{} on {}", name, actual);
+                } else {
+                    assert_eq!(
+                        Err(KvError::WrongType {
+                            expected: *expected,
+                            actual: *actual
+                        }),
+                        result,
+                        "{} on {}",
+                        name,
+                        actual
+                    );
+                }
+                assert_eq!(Some(*actual), type_of(&store, "k"), "{} on {}", name, actual);
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_overwrites_any_type() {
+        for ty in [
+            ItemType::Atomic,
+            ItemType::List,
+            ItemType::Set,
+            ItemType::SortedVec,
+            ItemType::SortedSet,
+            ItemType::Hash,
+        ]
+        .iter()
+        {
+            let mut store = holding(*ty);
+            store.set("k", b"new".to_vec()).unwrap();
+            assert_eq!(b"new".to_vec(), store.get("k").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_removing_from_missing_keys() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(0, store.srem("k", b"x".to_vec()).unwrap());
+        assert_eq!(0, store.lrem("k", b"x".to_vec()).unwrap());
+        assert_eq!(0, store.sv_tail_off::<i32>("k", 1).unwrap());
+        assert_eq!(0, store.sv_head_off::<i32>("k", 1).unwrap());
+        assert!(store.sv_pop_min::<i32>("k", 1).unwrap().is_empty());
+        assert_eq!(0, store.sv_remove_range_by_score::<i32>("k", Unbounded, Unbounded).unwrap());
+        assert!(store.sv_remove_item("k", (1, b"x".to_vec())).unwrap());
+        assert_eq!(0, store.zrem("k", &[b"x".to_vec()]).unwrap());
+        assert_eq!(None, type_of(&store, "k"));
+    }
+
+    fn list(values: &[&str]) -> Vec<Vec<u8>> {
+        values.iter().map(|v| v.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_push_and_pop() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(2, store.rpush("q", list(&["b", "c"])).unwrap());
+        assert_eq!(4, store.lpush("q", list(&["a", "z"])).unwrap());
+        assert_eq!(list(&["z", "a", "b", "c"]), store.lrange("q", 0, 10).unwrap());
+        assert_eq!(4, store.llen("q").unwrap());
+
+        assert_eq!(list(&["z"]), store.lpop("q", 1).unwrap());
+        assert_eq!(list(&["c", "b"]), store.rpop("q", 2).unwrap());
+        assert_eq!(list(&["a"]), store.lpop("q", 5).unwrap());
+        assert_eq!(false, store.exists("q").unwrap());
+        assert!(store.lpop("q", 1).unwrap().is_empty());
+        assert_eq!(0, store.llen("q").unwrap());
+    }
+
+    #[test]
+    fn test_list_index_and_set() {
+        let mut store = KeyValueStore::new();
+        store.rpush("l", list(&["a", "b", "c"])).unwrap();
+        assert_eq!(Some(b"a".to_vec()), store.lindex("l", 0).unwrap());
+        assert_eq!(Some(b"c".to_vec()), store.lindex("l", -1).unwrap());
+        assert_eq!(None, store.lindex("l", 3).unwrap());
+        assert_eq!(None, store.lindex("l", -4).unwrap());
+        assert_eq!(None, store.lindex("missing", 0).unwrap());
+
+        store.lset("l", -2, b"B".to_vec()).unwrap();
+        assert_eq!(list(&["a", "B", "c"]), store.lrange("l", 0, 10).unwrap());
+        match store.lset("l", 3, b"x".to_vec()).unwrap_err() {
+            KvError::OutOfRange(_) => {}
+            e => panic!("unexpected error {}", e),
+        }
+        assert_eq!(KvError::NoSuchKey, store.lset("missing", 0, b"x".to_vec()).unwrap_err());
+    }
+
+    #[test]
+    fn test_linsert() {
+        let mut store = KeyValueStore::new();
+        store.rpush("l", list(&["a", "c"])).unwrap();
+        assert_eq!(3, store.linsert("l", b"c", b"b".to_vec(), true).unwrap());
+        assert_eq!(4, store.linsert("l", b"c", b"d".to_vec(), false).unwrap());
+        assert_eq!(-1, store.linsert("l", b"x", b"y".to_vec(), true).unwrap());
+        assert_eq!(0, store.linsert("missing", b"x", b"y".to_vec(), true).unwrap());
+        assert_eq!(list(&["a", "b", "c", "d"]), store.lrange("l", 0, 10).unwrap());
+        assert_eq!(false, store.exists("missing").unwrap());
+    }
+
+    #[test]
+    fn test_ltrim() {
+        let mut store = KeyValueStore::new();
+        store.rpush("l", list(&["a", "b", "c", "d", "e"])).unwrap();
+        assert_eq!(3, store.ltrim("l", 1, -2).unwrap());
+        assert_eq!(list(&["b", "c", "d"]), store.lrange("l", 0, 10).unwrap());
+        assert_eq!(3, store.ltrim("l", -100, 100).unwrap());
+        assert_eq!(1, store.ltrim("l", -1, -1).unwrap());
+        assert_eq!(list(&["d"]), store.lrange("l", 0, 10).unwrap());
+        assert_eq!(0, store.ltrim("l", 2, 1).unwrap());
+        assert_eq!(false, store.exists("l").unwrap());
+    }
+
+    #[test]
+    fn test_lmove() {
+        let mut store = KeyValueStore::new();
+        store.rpush("jobs", list(&["1", "2", "3"])).unwrap();
+        assert_eq!(
+            Some(b"1".to_vec()),
+            store.lmove("jobs", "busy", ListEnd::Left, ListEnd::Right).unwrap()
+        );
+        assert_eq!(
+            Some(b"3".to_vec()),
+            store.lmove("jobs", "busy", ListEnd::Right, ListEnd::Left).unwrap()
+        );
+        assert_eq!(list(&["3", "1"]), store.lrange("busy", 0, 10).unwrap());
+        assert_eq!(
+            Some(b"2".to_vec()),
+            store.lmove("jobs", "jobs", ListEnd::Left, ListEnd::Right).unwrap()
+        );
+        assert_eq!(list(&["2"]), store.lrange("jobs", 0, 10).unwrap());
+        assert_eq!(None, store.lmove("missing", "busy", ListEnd::Left, ListEnd::Left).unwrap());
+
+        store.set("scalar", b"x".to_vec()).unwrap();
+        assert!(store.lmove("jobs", "scalar", ListEnd::Left, ListEnd::Left).is_err());
+        assert_eq!(list(&["2"]), store.lrange("jobs", 0, 10).unwrap());
+    }
+
+    #[test]
+    fn test_set_membership() {
+        let store = gen_store();
+        assert!(store.sismember("test", b"bob").unwrap());
+        assert_eq!(false, store.sismember("test", b"carol").unwrap());
+        assert_eq!(false, store.sismember("missing", b"bob").unwrap());
+        assert_eq!(
+            vec![true, false, true],
+            store
+                .smismember("test", &list(&["bob", "carol", "alice"]))
+                .unwrap()
+        );
+        assert_eq!(vec![false, false], store.smismember("missing", &list(&["a", "b"])).unwrap());
+        assert_eq!(3, store.scard("test").unwrap());
+        assert_eq!(0, store.scard("missing").unwrap());
+        assert!(store.sismember("setkey", b"x").is_err());
+    }
+
+    #[test]
+    fn test_sdiff() {
+        let store = gen_store();
+        assert_eq!(list(&["alice"]), store.sdiff(&["test".to_string(), "test2".to_string()]).unwrap());
+        let mut diff = store.sdiff(&["test".to_string(), "missing".to_string()]).unwrap();
+        diff.sort();
+        assert_eq!(list(&["alice", "bob", "dave"]), diff);
+        assert!(store.sdiff(&["missing".to_string(), "test".to_string()]).unwrap().is_empty());
+        assert!(store.sdiff(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_store_variants() {
+        let mut store = gen_store();
+        let keys = vec!["test".to_string(), "test2".to_string()];
+        assert_eq!(3, store.sunionstore("union", keys.clone()).unwrap());
+        assert_eq!(2, store.sinterstore("inter", keys.clone()).unwrap());
+        assert_eq!(1, store.sdiffstore("diff", &keys).unwrap());
+        assert_eq!(list(&["alice"]), store.smembers("diff".to_string()).unwrap());
+        assert!(store.sismember("inter", b"dave").unwrap());
+        assert_eq!(false, store.sismember("inter", b"alice").unwrap());
+
+        // The destination is overwritten whatever it held, and an empty result removes it
+        store.expire("setkey", 60_000).unwrap();
+        assert_eq!(3, store.sunionstore("setkey", keys.clone()).unwrap());
+        assert_eq!(3, store.scard("setkey").unwrap());
+        assert_eq!(TTL_NO_EXPIRY, store.ttl("setkey").unwrap());
+        assert_eq!(0, store.sdiffstore("setkey", &["test2".to_string(), "test".to_string()]).unwrap());
+        assert_eq!(false, store.exists("setkey").unwrap());
+
+        // A source can also be the destination
+        assert_eq!(2, store.sinterstore("test", keys).unwrap());
+        assert_eq!(2, store.scard("test").unwrap());
+    }
+
+    #[test]
+    fn test_spop_and_srandmember() {
+        let mut store = KeyValueStore::new();
+        for m in 0..10 {
+            store.sadd("s", vec![m]).unwrap();
+        }
+        store.set_rng(Box::new(StdRng::seed_from_u64(7)));
+        let sample = store.srandmember("s", 4).unwrap();
+        store.set_rng(Box::new(StdRng::seed_from_u64(7)));
+        assert_eq!(sample, store.srandmember("s", 4).unwrap());
+        assert_eq!(4, sample.iter().collect::<HashSet<_>>().len());
+        // The same members and seed pick the same members in any other store
+        let mut other = KeyValueStore::new();
+        for m in (0..10).rev() {
+            other.sadd("s", vec![m]).unwrap();
+        }
+        other.set_rng(Box::new(StdRng::seed_from_u64(7)));
+        assert_eq!(sample, other.srandmember("s", 4).unwrap());
+        assert_eq!(10, store.srandmember("s", 100).unwrap().len());
+        assert!(store.srandmember("missing", 3).unwrap().is_empty());
+
+        store.set_rng(Box::new(StdRng::seed_from_u64(7)));
+        let popped = store.spop("s", 4).unwrap();
+        assert_eq!(sample, popped);
+        assert_eq!(6, store.scard("s").unwrap());
+        for member in popped.iter() {
+            assert_eq!(false, store.sismember("s", member).unwrap());
+        }
+        assert_eq!(6, store.spop("s", 100).unwrap().len());
+        assert_eq!(0, store.scard("s").unwrap());
+        assert!(store.spop("missing", 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_empty_sets_are_removed() {
+        let mut store = KeyValueStore::new();
+        store.sadd("a", b"x".to_vec()).unwrap();
+        assert_eq!(0, store.srem("a", b"x".to_vec()).unwrap());
+        assert!(!store.exists("a").unwrap());
+
+        store.sadd("b", b"x".to_vec()).unwrap();
+        store.sadd("b", b"y".to_vec()).unwrap();
+        assert_eq!(0, store.srem_members("b", &[b"x".to_vec(), b"y".to_vec()]).unwrap());
+        assert!(!store.exists("b").unwrap());
+
+        store.sadd("c", b"x".to_vec()).unwrap();
+        assert_eq!(1, store.spop("c", 5).unwrap().len());
+        assert!(!store.exists("c").unwrap());
+
+        store.sadd("d", b"x".to_vec()).unwrap();
+        assert!(store.smove("d", "e", b"x".to_vec()).unwrap());
+        assert!(!store.exists("d").unwrap());
+        assert!(store.sismember("e", b"x").unwrap());
+
+        assert_eq!(0, store.zadd("ranked", vec![]).unwrap());
+        assert!(!store.exists("ranked").unwrap());
+        store.zadd("ranked", vec![(b"bob".to_vec(), 1)]).unwrap();
+        assert_eq!(0, store.zadd("ranked", vec![]).unwrap());
+        assert_eq!(1, store.zcard("ranked").unwrap());
+    }
+
+    #[test]
+    fn test_smove() {
+        let mut store = gen_store();
+        assert!(store.smove("test", "moved", b"bob".to_vec()).unwrap());
+        assert_eq!(false, store.sismember("test", b"bob").unwrap());
+        assert!(store.sismember("moved", b"bob").unwrap());
+        assert_eq!(false, store.smove("test", "moved", b"bob".to_vec()).unwrap());
+        assert_eq!(false, store.smove("missing", "moved", b"bob".to_vec()).unwrap());
+
+        assert!(store.smove("test", "setkey", b"alice".to_vec()).is_err());
+        assert!(store.sismember("test", b"alice").unwrap());
+    }
+
+    #[test]
+    fn test_incr() {
+        let mut store = gen_store();
+
+        let a = store.incr("counter", 1).unwrap();
+        let b = store.incr("counter", 1).unwrap();
+        let c = store.incr("counter", -3).unwrap();
+
+        assert_eq!(a, 6);
+        assert_eq!(b, 7);
+        assert_eq!(c, 4);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut store = KeyValueStore::new();
+        store.set_ex("session", b"old".to_vec(), 60_000).unwrap();
+        store.rpush("queue", list(&["job"])).unwrap();
+        let writes = store.write_count();
+        let versions = (store.version("session").unwrap(), store.version("queue").unwrap());
+
+        let result = store.transaction(|s| {
+            let job = s.lpop("queue", 1)?;
+            s.sadd("done", job[0].clone())?;
+            s.set("session", b"new".to_vec())?;
+            s.incr_by("created", 1)?;
+            s.incr("session", 1).map(|_| ())
+        });
+        assert_eq!(
+            Err(KvError::WrongType {
+                expected: ItemType::Atomic,
+                actual: ItemType::Scalar
+            }),
+            result
+        );
+        assert_eq!(list(&["job"]), store.lrange("queue", 0, -1).unwrap());
+        assert_eq!(None, type_of(&store, "done"));
+        assert_eq!(None, type_of(&store, "created"));
+        assert_eq!(b"old".to_vec(), store.get("session").unwrap());
+        assert!(store.ttl("session").unwrap() > 0);
+        assert_eq!(writes, store.write_count());
+        assert_eq!(versions, (store.version("session").unwrap(), store.version("queue").unwrap()));
+        assert_eq!(0, store.version("done").unwrap());
+
+        store
+            .transaction(|s| {
+                let job = s.lpop("queue", 1)?;
+                s.sadd("done", job[0].clone()).map(|_| ())
+            })
+            .unwrap();
+        assert_eq!(0, store.llen("queue").unwrap());
+        assert!(store.sismember("done", b"job").unwrap());
+        assert!(store.transaction(|s| s.transaction(|_| Ok(()))).is_err());
+    }
+
+    #[test]
+    fn test_multi_key() {
+        let mut store = KeyValueStore::new();
+        store.set("a", b"1".to_vec()).unwrap();
+        let pairs = |keys: &[&str]| keys.iter().map(|k| (k.to_string(), k.as_bytes().to_vec())).collect();
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(vec![true, false], store.mset(pairs(&["a", "b"])).unwrap());
+        assert_eq!(
+            vec![Some(b"a".to_vec()), None, Some(b"b".to_vec())],
+            store.mget(&keys(&["a", "c", "b"])).unwrap()
+        );
+
+        assert_eq!(vec![false, true], store.msetnx(pairs(&["c", "b"])).unwrap());
+        assert!(!store.exists("c").unwrap());
+        assert_eq!(vec![false, false], store.msetnx(pairs(&["c", "d"])).unwrap());
+        assert_eq!(b"d".to_vec(), store.get("d").unwrap());
+
+        store.sadd("set", b"m".to_vec()).unwrap();
+        assert!(store.mget(&keys(&["a", "set"])).is_err());
+
+        assert_eq!(vec![true, false, true, false], store.mdel(&keys(&["a", "x", "set", "a"])).unwrap());
+        assert_eq!(vec![None, Some(b"b".to_vec())], store.mget(&keys(&["a", "b"])).unwrap());
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut store = KeyValueStore::new();
+        for i in 0..10 {
+            store.set(&format!("ns/user:{}:session", i), vec![]).unwrap();
+        }
+        store.sadd("ns/user:x:roles", b"admin".to_vec()).unwrap();
+        store.set("ns/user:gone:session", vec![]).unwrap();
+        store.expire_at("ns/user:gone:session", 1).unwrap();
+        store.set("other/user:1:session", vec![]).unwrap();
+
+        let mut cursor = None;
+        let mut found = Vec::new();
+        let mut pages = 0;
+        loop {
+            let (keys, next) = store.scan("ns/", cursor.as_deref(), 3, Some("user:*:session"), None).unwrap();
+            found.extend(keys);
+            pages += 1;
+            if pages == 2 {
+                // Writes between pages neither repeat nor skip keys that stay put
+                store.del("ns/user:0:session").unwrap();
+                store.set("ns/user:9:session", b"changed".to_vec()).unwrap();
+                store.set("ns/user:5a:session", vec![]).unwrap();
+            }
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        let expected: Vec<String> = ["0", "1", "2", "3", "4", "5", "5a", "6", "7", "8", "9"]
+            .iter()
+            .map(|i| format!("ns/user:{}:session", i))
+            .collect();
+        assert_eq!(expected, found);
+        assert_eq!(5, pages);
+
+        let (sets, next) = store.scan("ns/", None, 100, None, Some(ItemType::Set)).unwrap();
+        assert_eq!(vec!["ns/user:x:roles".to_string()], sets);
+        assert_eq!(None, next);
+    }
+
+    #[test]
+    fn test_conditional_set() {
+        let mut store = KeyValueStore::new();
+        assert!(!store.set_if("leader", b"a".to_vec(), SetCondition::IfPresent).unwrap());
+        assert!(!store.exists("leader").unwrap());
+        assert!(store.set_if("leader", b"a".to_vec(), SetCondition::IfAbsent).unwrap());
+        assert!(!store.set_if("leader", b"b".to_vec(), SetCondition::IfAbsent).unwrap());
+        assert_eq!(b"a".to_vec(), store.get("leader").unwrap());
+        assert!(store.set_if("leader", b"c".to_vec(), SetCondition::IfPresent).unwrap());
+        assert!(store.set_if("leader", b"a".to_vec(), SetCondition::Always).unwrap());
+
+        store.expire("leader", 60_000).unwrap();
+        assert!(!store.compare_and_swap("leader", b"b", b"c".to_vec()).unwrap());
+        assert!(store.compare_and_swap("leader", b"a", b"b".to_vec()).unwrap());
+        assert_eq!(b"b".to_vec(), store.get("leader").unwrap());
+        assert!(store.ttl("leader").unwrap() > 0);
+        assert!(!store.compare_and_swap("missing", b"", b"x".to_vec()).unwrap());
+        assert!(!store.exists("missing").unwrap());
+
+        assert_eq!(Some(b"b".to_vec()), store.getset("leader", b"d".to_vec()).unwrap());
+        assert_eq!(TTL_NO_EXPIRY, store.ttl("leader").unwrap());
+        assert_eq!(None, store.getset("fresh", b"x".to_vec()).unwrap());
+        assert_eq!(b"x".to_vec(), store.get("fresh").unwrap());
+
+        store.sadd("set", b"m".to_vec()).unwrap();
+        assert!(store.getset("set", b"x".to_vec()).is_err());
+        assert!(store.compare_and_swap("set", b"m", b"x".to_vec()).is_err());
+        assert_eq!(Some(ItemType::Set), type_of(&store, "set"));
+        assert!(store.set_if("set", b"x".to_vec(), SetCondition::IfPresent).unwrap());
+        assert_eq!(Some(ItemType::Scalar), type_of(&store, "set"));
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(0, store.version("k").unwrap());
+        store.set("k", b"a".to_vec()).unwrap();
+        let created = store.version("k").unwrap();
+        assert!(created > 0);
+        store.get("k").unwrap();
+        assert_eq!(created, store.version("k").unwrap());
+
+        store.set("k", b"b".to_vec()).unwrap();
+        let updated = store.version("k").unwrap();
+        assert!(updated > created);
+        assert!(store.incr("k", 1).is_err());
+        assert_eq!(updated, store.version("k").unwrap());
+        store.expire("k", 60_000).unwrap();
+        let expiring = store.version("k").unwrap();
+        assert!(expiring > updated);
+
+        store.del("k").unwrap();
+        assert_eq!(0, store.version("k").unwrap());
+        store.set("k", b"a".to_vec()).unwrap();
+        assert!(store.version("k").unwrap() > expiring);
+
+        store.rpush("list", list(&["a"])).unwrap();
+        store.lpop("list", 1).unwrap();
+        assert_eq!(0, store.version("list").unwrap());
+
+        // Keys loaded into a store created later get versions none of the old ones had
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let mut restored = KeyValueStore::new();
+        restored.restore("k", KeyValueItem::Scalar(b"a".to_vec()), None).unwrap();
+        assert!(restored.version("k").unwrap() > store.version("k").unwrap());
+    }
+
+    #[test]
+    fn test_writes_that_change_nothing_keep_versions() {
+        let mut store = KeyValueStore::new();
+        store.set("k", b"a".to_vec()).unwrap();
+        store.incr("counter", 1).unwrap();
+        store.hset("h", vec![("f".to_string(), b"1".to_vec())]).unwrap();
+        store.rpush("list", list(&["a"])).unwrap();
+        store.sv_insert("vec", &(1, b"a".to_vec()), true).unwrap();
+        store.sadd("set", b"x".to_vec()).unwrap();
+        let keys = ["k", "counter", "h", "list", "vec", "set"];
+        let versions: Vec<u64> = keys.iter().map(|k| store.version(k).unwrap()).collect();
+        let writes = store.write_count();
+
+        assert!(!store.compare_and_swap("k", b"b", b"c".to_vec()).unwrap());
+        assert!(!store.set_if("k", b"c".to_vec(), SetCondition::IfAbsent).unwrap());
+        assert_eq!(1, store.incr("counter", 0).unwrap());
+        assert!(!store.hsetnx("h", "f".to_string(), b"2".to_vec()).unwrap());
+        assert_eq!(0, store.hset("h", vec![("f".to_string(), b"1".to_vec())]).unwrap());
+        assert_eq!(-1, store.linsert("list", b"missing", b"b".to_vec(), true).unwrap());
+        assert!(!store.sv_insert("vec", &(1, b"b".to_vec()), false).unwrap());
+        assert_eq!(1, store.sadd("set", b"x".to_vec()).unwrap());
+        assert_eq!(1, store.srem("set", b"y".to_vec()).unwrap());
+        assert_eq!(0, store.rpush("missing", vec![]).unwrap());
+        assert!(!store.exists("missing").unwrap());
+        store.del("missing").unwrap();
+        assert!(!store.expire("missing", 60_000).unwrap());
+        assert!(!store.persist("k").unwrap());
+        assert_eq!(versions, keys.iter().map(|k| store.version(k).unwrap()).collect::<Vec<_>>());
+        assert_eq!(writes, store.write_count());
+
+        assert!(store.compare_and_swap("k", b"a", b"c".to_vec()).unwrap());
+        assert!(store.version("k").unwrap() > versions[0]);
+        assert_eq!(writes + 1, store.write_count());
+    }
+
+    #[test]
+    fn test_counter_overflow() {
+        let mut store = KeyValueStore::new();
+        let balance = 3_000_000_000i64;
+        assert_eq!(balance, store.incr_by("balance", balance).unwrap());
+        assert_eq!(balance - 1, store.decr_by("balance", 1).unwrap());
+        assert_eq!(-5, store.decr_by("debt", 5).unwrap());
+        assert_eq!(
+            KvError::OutOfRange("adding 1 to 2999999999 does not fit in an i32".into()),
+            store.incr("balance", 1).unwrap_err()
+        );
+
+        store.incr("small", i32::MAX).unwrap();
+        assert!(store.incr("small", 1).is_err());
+        assert_eq!(i32::MAX, store.incr("small", 0).unwrap());
+        assert_eq!(i64::from(i32::MAX) + 1, store.incr_by("small", 1).unwrap());
+
+        store.incr_by("big", i64::MAX).unwrap();
+        assert_eq!(
+            KvError::OutOfRange(format!("adding 1 to {} overflows", i64::MAX)),
+            store.incr_by("big", 1).unwrap_err()
+        );
+        assert!(store.decr_by("big", i64::MIN).is_err());
+        assert_eq!(i64::MAX - 2, store.decr_by("big", 2).unwrap());
+    }
+
+    #[test]
+    fn test_float_counter() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(1.5, store.incr_by_float("temp", 1.5).unwrap());
+        assert_eq!(1.25, store.incr_by_float("temp", -0.25).unwrap());
+        assert!(store.incr_by_float("temp", f64::INFINITY).is_err());
+        assert!(store.incr_by_float("temp", f64::NAN).is_err());
+        assert_eq!(1.25, store.incr_by_float("temp", 0.0).unwrap());
+        assert_eq!(
+            KvError::WrongType {
+                expected: ItemType::Atomic,
+                actual: ItemType::Float
+            },
+            store.incr_by("temp", 1).unwrap_err()
+        );
+        store.incr_by("hits", 1).unwrap();
+        assert!(store.incr_by_float("hits", 1.0).is_err());
+    }
+
+    #[test]
+    fn test_exists_and_del() {
+        let mut store = gen_store();
 
         store.set("thenumber", "42".to_owned().into_bytes());
         assert!(store.exists("thenumber").unwrap());
@@ -379,6 +2415,60 @@ mod test {
         store.set("thenumber", "41".to_owned().into_bytes());
         assert!(store.exists("thenumber").unwrap());
     }
+    #[test]
+    fn test_expire() {
+        let mut store = gen_store();
+
+        assert_eq!(TTL_NO_EXPIRY, store.ttl("setkey").unwrap());
+        assert_eq!(TTL_NO_KEY, store.ttl("nosuchkey").unwrap());
+        assert_eq!(false, store.expire("nosuchkey", 1000).unwrap());
+
+        assert!(store.expire("setkey", 60_000).unwrap());
+        let ttl = store.ttl("setkey").unwrap();
+        assert!(ttl > 0 && ttl <= 60_000);
+        assert!(store.persist("setkey").unwrap());
+        assert_eq!(TTL_NO_EXPIRY, store.ttl("setkey").unwrap());
+        assert_eq!(false, store.persist("setkey").unwrap());
+    }
+
+    #[test]
+    fn test_expired_keys_are_hidden() {
+        let mut store = gen_store();
+        let past = now_millis() - 1;
+
+        for key in &["test", "list1", "counter", "setkey"] {
+            assert!(store.expire_at(key, past).unwrap());
+            assert_eq!(false, store.exists(key).unwrap());
+            assert_eq!(TTL_NO_KEY, store.ttl(key).unwrap());
+        }
+        assert!(store.get("setkey").is_err());
+        assert!(store.smembers("test".to_string()).unwrap().is_empty());
+        let union = store
+            .sunion(vec!["test".to_string(), "test2".to_string()])
+            .unwrap();
+        assert_eq!(2, union.len());
+        assert_eq!(1, store.incr("counter", 1).unwrap());
+        assert_eq!(TTL_NO_EXPIRY, store.ttl("counter").unwrap());
+    }
+
+    #[test]
+    fn test_set_ex_and_sweep() {
+        let mut store = gen_store();
+        let past = now_millis() - 1;
+
+        store.set_ex("session", "token".to_owned().into_bytes(), 60_000).unwrap();
+        assert!(store.ttl("session").unwrap() > 0);
+        store.set("session", "token2".to_owned().into_bytes()).unwrap();
+        assert_eq!(TTL_NO_EXPIRY, store.ttl("session").unwrap());
+
+        store.expire_at("test", past).unwrap();
+        store.expire_at("list1", past).unwrap();
+        store.expire("setkey", 60_000).unwrap();
+        assert_eq!(2, store.sweep().unwrap());
+        assert_eq!(0, store.sweep().unwrap());
+        assert!(store.exists("setkey").unwrap());
+    }
+
     #[test]
     fn test_sorted_vec(){
         let mut store = gen_store();
@@ -394,8 +2484,298 @@ mod test {
         store.sv_insert("sorted", &tup2, false);
         let r = store.sv_into_vec("sorted").unwrap();
         assert_eq!(r, vec![tup0.clone(),tup1.clone(),tup2.clone(),tup3.clone()]);
-        store.sv_tail_off("sorted", 2);
+        store.sv_tail_off::<i32>("sorted", 2);
         let r = store.sv_into_vec("sorted").unwrap();
         assert_eq!(r, vec![tup0.clone(),tup1.clone()]);
     }
-}
\ No newline at end of file
+
+    fn entries(scores: &[i32]) -> Vec<(i32, Vec<u8>)> {
+        scores.iter().map(|s| (*s, s.to_string().into_bytes())).collect()
+    }
+
+    #[test]
+    fn test_sorted_vec_ranges() {
+        let mut store = KeyValueStore::new();
+        for entry in entries(&[50, 10, 40, 20, 30]).iter() {
+            store.sv_insert("board", entry, false).unwrap();
+        }
+
+        assert_eq!(
+            entries(&[20, 30, 40]),
+            store.sv_range_by_score("board", Included(20), Included(40), 0, None, false).unwrap()
+        );
+        assert_eq!(
+            entries(&[30]),
+            store.sv_range_by_score("board", Excluded(20), Excluded(40), 0, None, false).unwrap()
+        );
+        assert_eq!(
+            entries(&[30, 20]),
+            store.sv_range_by_score("board", Unbounded, Excluded(50), 1, Some(2), true).unwrap()
+        );
+        assert!(store.sv_range_by_score("board", Included(40), Included(20), 0, None, false).unwrap().is_empty());
+
+        assert_eq!(entries(&[50, 40, 30]), store.sv_range_by_rank("board", 0, Some(3), true).unwrap());
+        assert_eq!(entries(&[20, 30]), store.sv_range_by_rank("board", 1, Some(2), false).unwrap());
+        assert_eq!(entries(&[40, 50]), store.sv_range_by_rank("board", 3, None, false).unwrap());
+        assert!(store.sv_range_by_rank::<i32>("board", 9, Some(2), true).unwrap().is_empty());
+
+        assert_eq!(4, store.sv_count("board", Included(20), Unbounded).unwrap());
+        assert_eq!(0, store.sv_count::<i32>("missing", Unbounded, Unbounded).unwrap());
+        assert!(store.sv_range_by_rank::<i32>("missing", 0, None, false).unwrap().is_empty());
+
+        store.rpush("list", vec![b"x".to_vec()]).unwrap();
+        assert!(store.sv_range_by_rank::<i32>("list", 0, None, false).is_err());
+    }
+
+    #[test]
+    fn test_wide_scores() {
+        let mut store = KeyValueStore::new();
+        let late = 1_600_000_000_000i64;
+        store.sv_insert("events", &(late, b"late".to_vec()), false).unwrap();
+        store.sv_insert("events", &(late - 1, b"early".to_vec()), false).unwrap();
+        assert_eq!(
+            vec![(late, b"late".to_vec())],
+            store.sv_range_by_score("events", Excluded(late - 1), Unbounded, 0, None, false).unwrap()
+        );
+        assert_eq!(
+            KvError::WrongType {
+                expected: ItemType::SortedVec,
+                actual: ItemType::SortedVecI64
+            },
+            store.sv_insert("events", &(1, b"x".to_vec()), false).unwrap_err()
+        );
+        assert!(store.sv_into_vec::<i32>("events").is_err());
+        assert!(store.sv_tail_off::<u64>("events", 0).is_err());
+        assert_eq!(2, store.sv_count::<i64>("events", Unbounded, Unbounded).unwrap());
+
+        store.sv_insert("heights", &(u64::MAX, b"top".to_vec()), false).unwrap();
+        assert_eq!(vec![(u64::MAX, b"top".to_vec())], store.sv_pop_max("heights", 1).unwrap());
+        // A drained key is gone, so it can take another score type
+        assert!(!store.exists("heights").unwrap());
+        store.sv_insert("heights", &(F64(0.5), b"half".to_vec()), false).unwrap();
+
+        store.sv_insert("prices", &(F64(1.5), b"b".to_vec()), false).unwrap();
+        store.sv_insert("prices", &(F64(-0.25), b"a".to_vec()), false).unwrap();
+        assert_eq!(
+            vec![(F64(-0.25), b"a".to_vec()), (F64(1.5), b"b".to_vec())],
+            store.sv_into_vec("prices").unwrap()
+        );
+        assert_eq!(Some(ItemType::SortedVecF64), store.item_type("prices").unwrap());
+    }
+
+    #[test]
+    fn test_sorted_vec_as_queue() {
+        let mut store = KeyValueStore::new();
+        for entry in entries(&[50, 10, 40, 20, 30, 60, 70]).iter() {
+            store.sv_insert("jobs", entry, false).unwrap();
+        }
+
+        assert_eq!(entries(&[10, 20]), store.sv_pop_min("jobs", 2).unwrap());
+        assert_eq!(entries(&[70]), store.sv_pop_max("jobs", 1).unwrap());
+        assert_eq!(entries(&[60, 50]), store.sv_pop_max("jobs", 2).unwrap());
+        assert_eq!(entries(&[30, 40]), store.sv_into_vec("jobs").unwrap());
+        assert_eq!(entries(&[30, 40]), store.sv_pop_min("jobs", 5).unwrap());
+        assert!(!store.exists("jobs").unwrap());
+        assert!(store.sv_pop_max::<i32>("jobs", 1).unwrap().is_empty());
+
+        for entry in entries(&[1, 2, 3, 4, 5, 6]).iter() {
+            store.sv_insert("jobs", entry, false).unwrap();
+        }
+        assert_eq!(4, store.sv_head_off::<i32>("jobs", 4).unwrap());
+        assert_eq!(entries(&[3, 4, 5, 6]), store.sv_into_vec("jobs").unwrap());
+        assert_eq!(4, store.sv_head_off::<i32>("jobs", 10).unwrap());
+        assert_eq!(2, store.sv_remove_range_by_score("jobs", Excluded(3), Included(5)).unwrap());
+        assert_eq!(entries(&[3, 6]), store.sv_into_vec("jobs").unwrap());
+        assert_eq!(0, store.sv_remove_range_by_score("jobs", Included(4), Included(5)).unwrap());
+
+        assert!(store.sv_remove_item("jobs", (3, b"3".to_vec())).unwrap());
+        assert_eq!(0, store.sv_tail_off::<i32>("jobs", 0).unwrap());
+        assert!(!store.exists("jobs").unwrap());
+        store.sv_insert("jobs", &(1, b"1".to_vec()), false).unwrap();
+        assert_eq!(1, store.sv_remove_range_by_score::<i32>("jobs", Unbounded, Unbounded).unwrap());
+        assert!(!store.exists("jobs").unwrap());
+        store.sv_insert("jobs", &(1, b"1".to_vec()), false).unwrap();
+        assert_eq!(0, store.sv_head_off::<i32>("jobs", 0).unwrap());
+        assert!(!store.exists("jobs").unwrap());
+    }
+
+    fn scored(members: &[(&str, i64)]) -> Vec<(Vec<u8>, i64)> {
+        members.iter().map(|(m, s)| (m.as_bytes().to_vec(), *s)).collect()
+    }
+
+    #[test]
+    fn test_sorted_set() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(3, store.zadd("board", scored(&[("bob", 20), ("alice", 10), ("carol", 10)])).unwrap());
+        assert_eq!(0, store.zadd("board", scored(&[("bob", 5)])).unwrap());
+        assert_eq!(
+            scored(&[("bob", 5), ("alice", 10), ("carol", 10)]),
+            store.zrange("board", 0, -1).unwrap()
+        );
+        assert_eq!(scored(&[("carol", 10)]), store.zrange("board", -1, -1).unwrap());
+
+        assert_eq!(25, store.zincrby("board", b"alice".to_vec(), 15).unwrap());
+        assert_eq!(-1, store.zincrby("board", b"dave".to_vec(), -1).unwrap());
+        assert_eq!(Some(25), store.zscore("board", b"alice").unwrap());
+        assert_eq!(None, store.zscore("board", b"erin").unwrap());
+        assert_eq!(Some(3), store.zrank("board", b"alice").unwrap());
+        assert_eq!(Some(0), store.zrevrank("board", b"alice").unwrap());
+        assert_eq!(Some(3), store.zrevrank("board", b"dave").unwrap());
+        assert_eq!(None, store.zrank("missing", b"alice").unwrap());
+        assert_eq!(4, store.zcard("board").unwrap());
+
+        assert_eq!(
+            KvError::OutOfRange("adding 9223372036854775807 to a score of 25 overflows".into()),
+            store.zincrby("board", b"alice".to_vec(), i64::MAX).unwrap_err()
+        );
+        assert_eq!(Some(25), store.zscore("board", b"alice").unwrap());
+
+        assert_eq!(2, store.zrem("board", &list(&["alice", "bob", "erin"])).unwrap());
+        assert_eq!(2, store.zrem("board", &list(&["carol", "dave"])).unwrap());
+        assert_eq!(None, type_of(&store, "board"));
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(String, Vec<u8>)> {
+        fields.iter().map(|(f, v)| (f.to_string(), v.as_bytes().to_vec())).collect()
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(2, store.hset("user", fields(&[("name", "ann"), ("city", "oslo")])).unwrap());
+        assert_eq!(1, store.hset("user", fields(&[("city", "rome"), ("age", "41")])).unwrap());
+        assert_eq!(0, store.hset("empty", vec![]).unwrap());
+        assert_eq!(None, type_of(&store, "empty"));
+
+        assert_eq!(Some(b"rome".to_vec()), store.hget("user", "city").unwrap());
+        assert_eq!(None, store.hget("user", "zip").unwrap());
+        assert_eq!(None, store.hget("missing", "city").unwrap());
+        let names: Vec<String> = vec!["name".into(), "zip".into()];
+        assert_eq!(vec![Some(b"ann".to_vec()), None], store.hmget("user", &names).unwrap());
+        assert_eq!(vec![None, None], store.hmget("missing", &names).unwrap());
+        assert!(store.hexists("user", "age").unwrap());
+        assert!(!store.hexists("missing", "age").unwrap());
+        assert_eq!(3, store.hlen("user").unwrap());
+        assert_eq!(vec!["age", "city", "name"], store.hkeys("user").unwrap());
+        assert_eq!(list(&["41", "rome", "ann"]), store.hvals("user").unwrap());
+        assert_eq!(
+            fields(&[("age", "41"), ("city", "rome"), ("name", "ann")]),
+            store.hgetall("user").unwrap()
+        );
+
+        assert!(!store.hsetnx("user", "name".into(), b"bob".to_vec()).unwrap());
+        assert!(store.hsetnx("user", "zip".into(), b"00100".to_vec()).unwrap());
+        assert_eq!(Some(b"ann".to_vec()), store.hget("user", "name").unwrap());
+
+        assert_eq!(42, store.hincrby("user", "age".into(), 1).unwrap());
+        assert_eq!(-3, store.hincrby("user", "visits".into(), -3).unwrap());
+        assert_eq!(Some(b"42".to_vec()), store.hget("user", "age").unwrap());
+        assert_eq!(
+            KvError::OutOfRange("field name does not hold an integer".into()),
+            store.hincrby("user", "name".into(), 1).unwrap_err()
+        );
+        assert_eq!(
+            KvError::OutOfRange("adding 9223372036854775807 to a value of 42 overflows".into()),
+            store.hincrby("user", "age".into(), i64::MAX).unwrap_err()
+        );
+        assert_eq!(Some(b"42".to_vec()), store.hget("user", "age").unwrap());
+
+        assert_eq!(1, store.hdel("user", &["zip".to_string(), "nope".to_string()]).unwrap());
+        assert_eq!(0, store.hdel("missing", &["zip".to_string()]).unwrap());
+        let all = store.hkeys("user").unwrap();
+        assert_eq!(4, store.hdel("user", &all).unwrap());
+        assert_eq!(None, type_of(&store, "user"));
+    }
+
+    /// Compares the set operations against a model built from plain `HashSet`s
+    mod model {
+        use super::super::KeyValueStore;
+        use proptest::collection::{hash_set, vec};
+        use proptest::prelude::*;
+        use std::collections::HashSet;
+
+        const KEYS: usize = 5;
+
+        /// Sets for some of the keys `k0` to `k4`. The others are missing.
+        fn sets() -> impl Strategy<Value = Vec<Option<HashSet<u8>>>> {
+            vec(proptest::option::of(hash_set(0u8..16, 0..8)), KEYS)
+        }
+
+        /// Keys to query, possibly repeated, possibly missing, possibly none
+        fn keys() -> impl Strategy<Value = Vec<usize>> {
+            vec(0..KEYS, 0..6)
+        }
+
+        fn build(sets: &[Option<HashSet<u8>>]) -> KeyValueStore {
+            let mut store = KeyValueStore::new();
+            for (i, set) in sets.iter().enumerate() {
+                if let Some(set) = set {
+                    for member in set {
+                        store.sadd(&format!("k{}", i), vec![*member]).unwrap();
+                    }
+                }
+            }
+            store
+        }
+
+        fn model(sets: &[Option<HashSet<u8>>], key: usize) -> HashSet<u8> {
+            sets[key].clone().unwrap_or_default()
+        }
+
+        fn names(keys: &[usize]) -> Vec<String> {
+            keys.iter().map(|k| format!("k{}", k)).collect()
+        }
+
+        fn result(members: Vec<Vec<u8>>) -> HashSet<u8> {
+            let len = members.len();
+            let set: HashSet<u8> = members.into_iter().map(|m| m[0]).collect();
+            assert_eq!(len, set.len(), "duplicate members in a result");
+            set
+        }
+
+        proptest! {
+            #[test]
+            fn sinter_matches_model(sets in sets(), keys in keys()) {
+                let store = build(&sets);
+                let mut expected: Option<HashSet<u8>> = None;
+                for k in keys.iter() {
+                    let set = model(&sets, *k);
+                    expected = Some(match expected {
+                        Some(acc) => acc.intersection(&set).cloned().collect(),
+                        None => set,
+                    });
+                }
+                let actual = result(store.sinter(names(&keys)).unwrap());
+                prop_assert_eq!(expected.unwrap_or_default(), actual);
+            }
+
+            #[test]
+            fn sunion_matches_model(sets in sets(), keys in keys()) {
+                let store = build(&sets);
+                let expected: HashSet<u8> = keys.iter().flat_map(|k| model(&sets, *k)).collect();
+                prop_assert_eq!(expected, result(store.sunion(names(&keys)).unwrap()));
+            }
+
+            #[test]
+            fn sdiff_matches_model(sets in sets(), keys in keys()) {
+                let store = build(&sets);
+                let mut expected = keys.first().map(|k| model(&sets, *k)).unwrap_or_default();
+                for k in keys.iter().skip(1) {
+                    expected = expected.difference(&model(&sets, *k)).cloned().collect();
+                }
+                prop_assert_eq!(expected, result(store.sdiff(&names(&keys)).unwrap()));
+            }
+
+            #[test]
+            fn store_variants_match_queries(sets in sets(), keys in keys()) {
+                let mut store = build(&sets);
+                let union = result(store.sunion(names(&keys)).unwrap());
+                let inter = result(store.sinter(names(&keys)).unwrap());
+                prop_assert_eq!(union.len(), store.sunionstore("union", names(&keys)).unwrap());
+                prop_assert_eq!(inter.len(), store.sinterstore("inter", names(&keys)).unwrap());
+                prop_assert_eq!(union, result(store.smembers("union".to_string()).unwrap()));
+                prop_assert_eq!(inter, result(store.smembers("inter".to_string()).unwrap()));
+            }
+        }
+    }
+}
@@ -37,27 +37,50 @@ extern crate wascc_codec as codec;
 extern crate log;
 
 
+mod backend;
+mod disk;
+mod error;
+mod frame;
+mod glob;
 mod kv;
-
-use crate::kv::KeyValueStore;
+mod namespace;
+mod oplog;
+mod ops;
+pub mod protocol;
+mod snapshot;
+mod sorted_vec;
+mod zset;
+
+use crate::backend::{DISK_STORAGE, MEMORY_STORAGE, STORAGE_KEY, STORAGE_PATH_KEY};
+use crate::disk::DiskBackend;
+use crate::error::KvError;
+use crate::kv::{now_millis, KeyValueStore};
+use crate::namespace::{Namespace, SHARED_NAMESPACE_KEY};
+use crate::oplog::{spawn_oplog_worker, OpLog, OpLogConfig, Record};
+use crate::snapshot::{spawn_snapshotter, Snapshot, SnapshotConfig};
 use codec::capabilities::{CapabilityProvider, Dispatcher, NullDispatcher};
 use codec::core::{OP_BIND_ACTOR, OP_REMOVE_ACTOR};
-use tea_codec::keyvalue;
-use tea_codec::keyvalue::*;
 use wascc_codec::core::CapabilityConfiguration;
-use wascc_codec::{deserialize, serialize};
+use wascc_codec::deserialize;
 
+use std::collections::HashMap;
 use std::error::Error;
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
 
 #[cfg(not(feature = "static_plugin"))]
 capability_provider!(KeyvalueProvider, KeyvalueProvider::new);
 
 const CAPABILITY_ID: &str = "tea:keyvalue";
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct KeyvalueProvider {
     dispatcher: RwLock<Box<dyn Dispatcher>>,
-    store: RwLock<KeyValueStore>,
+    store: Arc<RwLock<KeyValueStore>>,
+    namespaces: RwLock<HashMap<String, Namespace>>,
+    snapshot: RwLock<Option<SnapshotConfig>>,
+    oplog: Arc<Mutex<Option<OpLog>>>,
 }
 
 impl Default for KeyvalueProvider {
@@ -66,171 +89,212 @@ impl Default for KeyvalueProvider {
             Ok(_) => {}
             Err(_) => {}
         };
+        let store = Arc::new(RwLock::new(KeyValueStore::new()));
+        spawn_sweeper(Arc::downgrade(&store));
         KeyvalueProvider {
             dispatcher: RwLock::new(Box::new(NullDispatcher::new())),
-            store: RwLock::new(KeyValueStore::new()),
+            store,
+            namespaces: RwLock::new(HashMap::new()),
+            snapshot: RwLock::new(None),
+            oplog: Arc::new(Mutex::new(None)),
         }
     }
 }
 
+/// Periodically reclaims expired keys that nobody touches again. The thread
+/// exits once the provider, and with it the store, has been dropped.
+fn spawn_sweeper(store: Weak<RwLock<KeyValueStore>>) {
+    thread::spawn(move || loop {
+        thread::sleep(SWEEP_INTERVAL);
+        match store.upgrade() {
+            Some(store) => {
+                let mut store = store.write().unwrap();
+                match store.sweep() {
+                    Ok(0) => {}
+                    Ok(count) => trace!("Swept {} expired keys", count),
+                    Err(e) => error!("Sweeping expired keys failed: {}", e),
+                }
+                if let Err(e) = store.maintain() {
+                    error!("Storage maintenance failed: {}", e);
+                }
+            }
+            None => break,
+        }
+    });
+}
+
 impl KeyvalueProvider {
     pub fn new() -> Self {
         Self::default()
     }
 
-    fn configure(&self, _config: CapabilityConfiguration) -> Result<Vec<u8>, Box<dyn Error>> {
-        // Do nothing here
-        Ok(vec![])
-    }
-
-    fn remove_actor(&self, _config: CapabilityConfiguration) -> Result<Vec<u8>, Box<dyn Error>> {
-        // Do nothing here
+    fn configure(&self, config: CapabilityConfiguration) -> Result<Vec<u8>, Box<dyn Error>> {
+        if let Some(storage) = config.values.get(STORAGE_KEY) {
+            self.select_storage(storage, config.values.get(STORAGE_PATH_KEY))?;
+        }
+        if let Some(name) = config.values.get(SHARED_NAMESPACE_KEY) {
+            let ns = Namespace::shared(name)?;
+            info!("Actor {} joined shared namespace {}", config.module, name);
+            self.namespaces.write().unwrap().insert(config.module.clone(), ns);
+        }
+        if let Some(snapshot) = SnapshotConfig::from_values(&config.values)? {
+            self.enable_snapshots(snapshot)?;
+        }
+        if let Some(oplog) = OpLogConfig::from_values(&config.values)? {
+            self.enable_oplog(oplog)?;
+        }
         Ok(vec![])
     }
 
-    fn add(&self, _actor: &str, req: AddRequest) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut store = self.store.write().unwrap();
-        let res: i32 = store.incr(&req.key, req.value)?;
-        let resp = AddResponse { value: res };
-
-        Ok(serialize(resp)?)
-    }
-
-    fn del(&self, _actor: &str, req: DelRequest) -> Result<Vec<u8>, Box<dyn Error>> {
+    /// The storage backend is chosen by the first actor binding that names one,
+    /// before anything has been written. The disk backend persists every
+    /// write by itself, so it does not mix with snapshots or the operation log.
+    fn select_storage(&self, storage: &str, path: Option<&String>) -> Result<(), Box<dyn Error>> {
+        // Same lock order as `enable_snapshots` and `write`
+        let snapshot = self.snapshot.read().unwrap();
         let mut store = self.store.write().unwrap();
-        store.del(&req.key)?;
-        let resp = DelResponse { key: req.key };
-
-        Ok(serialize(resp)?)
-    }
-
-    fn get(&self, _actor: &str, req: GetRequest) -> Result<Vec<u8>, Box<dyn Error>> {
-        let store = self.store.read().unwrap();
-        if !store.exists(&req.key)? {
-            Ok(serialize(GetResponse {
-                value: vec![],
-                exists: false,
-            })?)
-        } else {
-            let v = store.get(&req.key);
-            Ok(serialize(match v {
-                Ok(s) => GetResponse {
-                    value: s,
-                    exists: true,
-                },
-                Err(e) => {
-                    eprint!("GET for {} failed: {}", &req.key, e);
-                    GetResponse {
-                        value: vec![],
-                        exists: false,
-                    }
+        let oplog = self.oplog.lock().unwrap();
+        if store.backend_name() == storage {
+            return Ok(());
+        }
+        if store.backend_name() != MEMORY_STORAGE {
+            warn!("Already using {} storage, ignoring {}", store.backend_name(), storage);
+            return Ok(());
+        }
+        match storage {
+            DISK_STORAGE => {
+                let path = path.ok_or("Disk storage needs a storage_path")?;
+                if snapshot.is_some() || oplog.is_some() {
+                    return Err("Disk storage can't be combined with snapshots or an operation log".into());
                 }
-            })?)
+                if !store.is_empty() {
+                    return Err("Storage can only be chosen before any key is written".into());
+                }
+                let backend = DiskBackend::open(path)?;
+                *store = KeyValueStore::with_backend(Box::new(backend));
+                info!("Storing keys in {}", path);
+                Ok(())
+            }
+            _ => Err(format!("Unknown storage backend: {}", storage).into()),
         }
     }
 
-    fn list_clear(&self, actor: &str, req: ListClearRequest) -> Result<Vec<u8>, Box<dyn Error>> {
-        self.del(actor, DelRequest { key: req.key })
-    }
-
-    fn list_range(&self, _actor: &str, req: ListRangeRequest) -> Result<Vec<u8>, Box<dyn Error>> {
-        let store = self.store.read().unwrap();
-        let result: Vec<Vec<u8>> = store.lrange(&req.key, req.start as _, req.stop as _)?;
-        Ok(serialize(ListRangeResponse { values: result })?)
-    }
-
-    fn list_push(&self, _actor: &str, req: ListPushRequest) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut store = self.store.write().unwrap();
-        let result: i32 = store.lpush(&req.key, req.value)?;
-        Ok(serialize(ListResponse { new_count: result })?)
-    }
-
-    fn set(&self, _actor: &str, req: SetRequest) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut store = self.store.write().unwrap();
-        store.set(&req.key, req.value.clone())?;
-        Ok(serialize(SetResponse { value: req.value })?)
-    }
-
-    fn list_del_item(
-        &self,
-        _actor: &str,
-        req: ListDelItemRequest,
-    ) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut store = self.store.write().unwrap();
-        let result: i32 = store.lrem(&req.key, req.value)?;
-        Ok(serialize(ListResponse { new_count: result })?)
-    }
-
-    fn set_add(&self, _actor: &str, req: SetAddRequest) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut store = self.store.write().unwrap();
-        let result: i32 = store.sadd(&req.key, req.value)?;
-        Ok(serialize(SetOperationResponse { new_count: result })?)
+    /// Snapshots are set up by the first actor binding that carries snapshot
+    /// settings. The existing snapshot, if any, is loaded at that point and
+    /// replaces whatever was written before.
+    fn enable_snapshots(&self, config: SnapshotConfig) -> Result<(), Box<dyn Error>> {
+        let mut current = self.snapshot.write().unwrap();
+        if self.store.read().unwrap().backend_name() != MEMORY_STORAGE {
+            return Err("Snapshots need memory storage".into());
+        }
+        if let Some(existing) = current.as_ref() {
+            if *existing != config {
+                warn!(
+                    "Snapshots already saved to {:?}, ignoring {:?}",
+                    existing.path, config.path
+                );
+            }
+            return Ok(());
+        }
+        if let Some(snapshot) = Snapshot::load(&config.path)? {
+            let mut store = self.store.write().unwrap();
+            // The operation log may already have brought the store further
+            if snapshot.seq() >= store.seq() {
+                info!("Loading {} keys from {:?}", snapshot.len(), config.path);
+                snapshot.restore(&mut store)?;
+            }
+        }
+        spawn_snapshotter(Arc::downgrade(&self.store), config.clone());
+        *current = Some(config);
+        Ok(())
     }
 
-    fn set_remove(&self, _actor: &str, req: SetRemoveRequest) -> Result<Vec<u8>, Box<dyn Error>> {
+    /// Like snapshots, the operation log is set up by the first actor binding
+    /// that asks for it and replayed on top of whatever is loaded already.
+    fn enable_oplog(&self, config: OpLogConfig) -> Result<(), Box<dyn Error>> {
+        // Same lock order as `write`: store first, then the log
         let mut store = self.store.write().unwrap();
-        let result: i32 = store.srem(&req.key, req.value)?;
-        Ok(serialize(SetOperationResponse { new_count: result })?)
-    }
-
-    fn set_union(&self, _actor: &str, req: SetUnionRequest) -> Result<Vec<u8>, Box<dyn Error>> {
-        let store = self.store.read().unwrap();
-        let result: Vec<Vec<u8>> = store.sunion(req.keys)?;
-        Ok(serialize(SetQueryResponse { values: result })?)
+        let mut current = self.oplog.lock().unwrap();
+        if store.backend_name() != MEMORY_STORAGE {
+            return Err("The operation log needs memory storage".into());
+        }
+        if let Some(existing) = current.as_ref() {
+            if *existing.config() != config {
+                warn!(
+                    "Operation log already written to {:?}, ignoring {:?}",
+                    existing.config().path,
+                    config.path
+                );
+            }
+            return Ok(());
+        }
+        let before = store.seq();
+        let oplog = OpLog::open(config, &mut store)?;
+        info!("Replayed operation log from #{} to #{}", before, store.seq());
+        *current = Some(oplog);
+        spawn_oplog_worker(Arc::downgrade(&self.store), Arc::downgrade(&self.oplog));
+        Ok(())
     }
 
-    fn set_intersect(
-        &self,
-        _actor: &str,
-        req: SetIntersectionRequest,
-    ) -> Result<Vec<u8>, Box<dyn Error>> {
-        let store = self.store.read().unwrap();
-        let result: Vec<Vec<u8>> = store.sinter(req.keys)?;
-        Ok(serialize(SetQueryResponse { values: result })?)
+    fn remove_actor(&self, config: CapabilityConfiguration) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.namespaces.write().unwrap().remove(&config.module);
+        Ok(vec![])
     }
 
-    fn set_query(&self, _actor: &str, req: SetQueryRequest) -> Result<Vec<u8>, Box<dyn Error>> {
-        let store = self.store.read().unwrap();
-        let result: Vec<Vec<u8>> = store.smembers(req.key)?;
-        Ok(serialize(SetQueryResponse { values: result })?)
+    /// Actors only see their own keys unless they were bound with a shared namespace
+    fn namespace(&self, actor: &str) -> Namespace {
+        self.namespaces
+            .read()
+            .unwrap()
+            .get(actor)
+            .cloned()
+            .unwrap_or_else(|| Namespace::actor(actor))
     }
 
-    fn exists(&self, _actor: &str, req: KeyExistsQuery) -> Result<Vec<u8>, Box<dyn Error>> {
-        let store = self.store.read().unwrap();
-        let result: bool = store.exists(&req.key)?;
-        Ok(serialize(GetResponse {
-            value: vec![],
-            exists: result,
-        })?)
-    }
-    fn sv_insert(&self, _actor:&str, req: KeyVecInsertQuery) -> Result<Vec<u8>, Box<dyn Error>>{
+    /// Applies a write under the store's write lock and records it in the
+    /// operation log before the lock is released, so the log order always
+    /// matches the order writes were applied in. Once an append has failed the
+    /// store holds a write the log is missing, so every later write is refused
+    /// before it is applied and the log stays a prefix of what was applied.
+    fn write(&self, ns: &Namespace, op: &str, msg: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        ops::validate(op, msg)?;
+        let (op, msg) = ops::absolute(op, msg)?;
         let mut store = self.store.write().unwrap();
-        let result: bool = store.sv_insert(&req.key, &req.value, req.overwrite)?;
-        Ok(serialize(KeyVecInsertResponse {
-           success:result,
-        })?)
+        let mut oplog = self.oplog.lock().unwrap();
+        if let Some(oplog) = oplog.as_ref() {
+            oplog.check()?;
+        }
+        let now = now_millis();
+        let resp = store.at(now, |store| ops::apply(store, ns, op, &msg))?;
+        if let Some(oplog) = oplog.as_mut() {
+            let (op, msg) = ops::deterministic(op, msg, &resp)?;
+            let seq = store.seq() + 1;
+            oplog.append(Record {
+                seq,
+                ns: ns.clone(),
+                op: op.to_string(),
+                msg: msg.into_owned(),
+                at_ms: now,
+            })?;
+            store.set_seq(seq);
+        }
+        Ok(resp)
     }
+}
 
-    fn sv_get(&self, _actor:&str, req: KeyVecGetQuery) -> Result<Vec<u8>, Box<dyn Error>>{
-        let store = self.store.read().unwrap();
-        let result: Vec<(i32, Vec<u8>)> = store.sv_into_vec(&req.key)?;
-        Ok(serialize(KeyVecGetResponse {
-            values: result,
-        })?)
-    }
-    fn sv_tail_off(&self, _actor:&str, req: KeyVecTailOffQuery) -> Result<Vec<u8>, Box<dyn Error>>{
-        let mut store = self.store.write().unwrap();
-        let result: usize = store.sv_tail_off(&req.key, req.remain)?;
-        Ok(serialize(KeyVecTailOffResponse {
-           len:result,
-        })?)
-    }
-    fn sv_remove_item(&self, _actor:&str, req: KeyVecRemoveItemQuery)-> Result<Vec<u8>, Box<dyn Error>>{
-        let mut store = self.store.write().unwrap();
-        let result: bool = store.sv_remove_item(&req.key, req.value)?;
-        Ok(serialize(KeyVecRemoveItemResponse {
-           success: result,
-        })?) 
+impl Drop for KeyvalueProvider {
+    fn drop(&mut self) {
+        if let Some(config) = self.snapshot.read().unwrap().as_ref() {
+            let snapshot = Snapshot::capture(&self.store.read().unwrap());
+            if let Err(e) = snapshot.and_then(|s| s.save(&config.path)) {
+                error!("Saving final snapshot to {:?} failed: {}", config.path, e);
+            }
+        }
+        if let Some(oplog) = self.oplog.lock().unwrap().as_mut() {
+            if let Err(e) = oplog.sync() {
+                error!("Syncing operation log failed: {}", e);
+            }
+        }
     }
 }
 
@@ -250,36 +314,101 @@ impl CapabilityProvider for KeyvalueProvider {
     }
 
     fn name(&self) -> &'static str {
-        "TEA Binary Key-Value Provider (In-Memory)"
+        "TEA Binary Key-Value Provider"
     }
 
     // Invoked by host runtime to allow an actor to make use of the capability
     // All providers MUST handle the "configure" message, even if no work will be done
     fn handle_call(&self, actor: &str, op: &str, msg: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
         trace!("Received host call from {}, operation - {}", actor, op);
+        let ns = self.namespace(actor);
 
-        match op {
-            OP_BIND_ACTOR if actor == "system" => self.configure(deserialize(msg)?),
-            OP_REMOVE_ACTOR if actor == "system" => self.remove_actor(deserialize(msg)?),
-            keyvalue::OP_ADD => self.add(actor, deserialize(msg)?),
-            keyvalue::OP_DEL => self.del(actor, deserialize(msg)?),
-            keyvalue::OP_GET => self.get(actor, deserialize(msg)?),
-            keyvalue::OP_CLEAR => self.list_clear(actor, deserialize(msg)?),
-            keyvalue::OP_RANGE => self.list_range(actor, deserialize(msg)?),
-            keyvalue::OP_PUSH => self.list_push(actor, deserialize(msg)?),
-            keyvalue::OP_SET => self.set(actor, deserialize(msg)?),
-            keyvalue::OP_LIST_DEL => self.list_del_item(actor, deserialize(msg)?),
-            keyvalue::OP_SET_ADD => self.set_add(actor, deserialize(msg)?),
-            keyvalue::OP_SET_REMOVE => self.set_remove(actor, deserialize(msg)?),
-            keyvalue::OP_SET_UNION => self.set_union(actor, deserialize(msg)?),
-            keyvalue::OP_SET_INTERSECT => self.set_intersect(actor, deserialize(msg)?),
-            keyvalue::OP_SET_QUERY => self.set_query(actor, deserialize(msg)?),
-            keyvalue::OP_KEY_EXISTS => self.exists(actor, deserialize(msg)?),
-            keyvalue::OP_KEYVEC_INSERT => self.sv_insert(actor, deserialize(msg)?),
-            keyvalue::OP_KEYVEC_GET => self.sv_get(actor, deserialize(msg)?),
-            keyvalue::OP_KEYVEC_TAILOFF =>self.sv_tail_off(actor, deserialize(msg)?),
-            keyvalue::OP_KEYVEC_REMOVE_ITEM =>self.sv_remove_item(actor, deserialize(msg)?),
-            _ => Err("bad dispatch".into()),
-        }
+        let result = match op {
+            OP_BIND_ACTOR if actor == "system" => deserialize(msg).and_then(|c| self.configure(c)),
+            OP_REMOVE_ACTOR if actor == "system" => deserialize(msg).and_then(|c| self.remove_actor(c)),
+            op if ops::is_write(op) => self.write(&ns, op, msg),
+            op => ops::query(&self.store.read().unwrap(), &ns, op, msg),
+        };
+        // Whatever went wrong, the actor gets one of the stable error codes
+        result.map_err(|e| {
+            let e = KvError::from(e);
+            trace!("{} from {} failed: {}", op, actor, e);
+            Box::new(e) as Box<dyn Error>
+        })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::KeyvalueProvider;
+    use crate::error::KvError;
+    use crate::kv::KeyValueStore;
+    use crate::namespace::Namespace;
+    use crate::oplog::{FsyncPolicy, OpLog, OpLogConfig};
+    use crate::protocol::{self, Command, ExecRequest};
+    use std::fs;
+    use tea_codec::keyvalue::{AddRequest, OP_ADD};
+    use wascc_codec::capabilities::CapabilityProvider;
+    use wascc_codec::serialize;
+
+    #[test]
+    fn test_failed_append_refuses_writes() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("tea-kvp-append-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = OpLogConfig {
+            path,
+            fsync: FsyncPolicy::Always,
+            rewrite_min_bytes: u64::MAX,
+        };
+        let provider = KeyvalueProvider::new();
+        provider.enable_oplog(config.clone()).unwrap();
+        let add = |key: &str| {
+            let req = AddRequest {
+                key: key.to_string(),
+                value: 1,
+            };
+            provider.handle_call("MTEST", OP_ADD, &serialize(req).unwrap())
+        };
+        let ns = Namespace::actor("MTEST");
+
+        add("logged").unwrap();
+        provider.oplog.lock().unwrap().as_mut().unwrap().break_appends().unwrap();
+        assert!(add("unlogged").is_err());
+        assert!(add("refused").is_err());
+        assert!(!provider.store.read().unwrap().exists(&ns.key("refused")).unwrap());
+        drop(provider);
+
+        let mut store = KeyValueStore::new();
+        OpLog::open(config.clone(), &mut store).unwrap();
+        assert!(store.exists(&ns.key("logged")).unwrap());
+        assert!(!store.exists(&ns.key("unlogged")).unwrap());
+        fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_nested_exec_is_rejected() {
+        let provider = KeyvalueProvider::new();
+        let add = serialize(AddRequest {
+            key: "counter".to_string(),
+            value: 1,
+        })
+        .unwrap();
+        let command = |op: &str, msg: Vec<u8>| Command {
+            op: op.to_string(),
+            msg,
+        };
+        let exec = |commands| {
+            serialize(ExecRequest {
+                watch: vec![],
+                commands,
+            })
+            .unwrap()
+        };
+        let inner = exec(vec![command(OP_ADD, add.clone())]);
+        let outer = exec(vec![command(OP_ADD, add), command(protocol::OP_EXEC, inner)]);
+        let e = KvError::from(provider.handle_call("MTEST", protocol::OP_EXEC, &outer).unwrap_err());
+        assert_eq!(protocol::ERR_INVALID_REQUEST, e.code());
+        let ns = Namespace::actor("MTEST");
+        assert!(!provider.store.read().unwrap().exists(&ns.key("counter")).unwrap());
+    }
+}
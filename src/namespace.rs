@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+/// Configuration value an actor can be bound with to join a shared namespace.
+/// Actors bound with the same value see the same keys; all other actors get
+/// a private namespace derived from their own identity.
+pub const SHARED_NAMESPACE_KEY: &str = "shared_namespace";
+
+const ACTOR_PREFIX: &str = "actor:";
+const SHARED_PREFIX: &str = "shared:";
+const SEPARATOR: char = '/';
+
+/// The portion of the flat `KeyValueStore` keyspace an actor is allowed to touch.
+/// Every key an actor sends is prefixed with its namespace before it reaches
+/// the store, so two actors can use the same key without clobbering each other.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Namespace(String);
+
+impl Namespace {
+    pub fn actor(actor: &str) -> Self {
+        Namespace(format!("{}{}", ACTOR_PREFIX, actor))
+    }
+
+    pub fn shared(name: &str) -> Result<Self, Box<dyn Error>> {
+        if name.is_empty() || name.contains(SEPARATOR) {
+            return Err(format!("Invalid shared namespace name: {:?}", name).into());
+        }
+        Ok(Namespace(format!("{}{}", SHARED_PREFIX, name)))
+    }
+
+    pub fn key(&self, key: &str) -> String {
+        format!("{}{}{}", self.0, SEPARATOR, key)
+    }
+
+    pub fn keys(&self, keys: Vec<String>) -> Vec<String> {
+        keys.iter().map(|k| self.key(k)).collect()
+    }
+
+    /// Takes the namespace back off a store key, or returns `None` if the key
+    /// belongs to another namespace
+    pub fn strip<'a>(&self, key: &'a str) -> Option<&'a str> {
+        key.strip_prefix(self.0.as_str())?.strip_prefix(SEPARATOR)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Namespace;
+
+    #[test]
+    fn test_isolation() {
+        let alice = Namespace::actor("MALICE");
+        let bob = Namespace::actor("MBOB");
+        assert_ne!(alice.key("counter"), bob.key("counter"));
+        assert_ne!(alice.key("counter"), Namespace::actor("MALI").key("cecounter"));
+        assert_eq!(Some("counter"), alice.strip(&alice.key("counter")));
+        assert_eq!(None, bob.strip(&alice.key("counter")));
+        assert_eq!(None, Namespace::actor("MALI").strip(&alice.key("counter")));
+    }
+
+    #[test]
+    fn test_shared() {
+        let shared = Namespace::shared("game").unwrap();
+        assert_eq!(shared, Namespace::shared("game").unwrap());
+        assert_ne!(shared.key("k"), Namespace::actor("game").key("k"));
+        assert!(Namespace::shared("").is_err());
+        assert!(Namespace::shared("a/b").is_err());
+    }
+}
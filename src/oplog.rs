@@ -0,0 +1,702 @@
+//! Append-only log of every write the provider applies, replayed on top of the
+//! last snapshot at startup.
+//!
+//! Records are framed as described in `frame`. A record that is cut short or
+//! fails its checksum marks the end of the usable log: everything from that
+//! point on is discarded and the file truncated, which is what a crash in the
+//! middle of an append leaves behind.
+//!
+//! The log compacts itself by rewriting the whole store as a single base
+//! record followed by whatever was appended while the rewrite was running.
+
+use crate::error::KvError;
+use crate::frame::{self, frame, replace, temp_path, HEADER_LEN};
+use crate::kv::KeyValueStore;
+use crate::namespace::Namespace;
+use crate::ops;
+use crate::snapshot::Snapshot;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// File the operation log is appended to. The log is disabled unless this is set.
+pub const OPLOG_PATH_KEY: &str = "oplog_path";
+/// One of `always`, `everysec` or `never`
+pub const OPLOG_FSYNC_KEY: &str = "oplog_fsync";
+/// The log is rewritten once it is larger than this and has doubled since the last rewrite
+pub const OPLOG_REWRITE_MIN_BYTES_KEY: &str = "oplog_rewrite_min_bytes";
+
+const MAGIC: &[u8; 4] = b"TKVL";
+const VERSION: u32 = 1;
+const DEFAULT_REWRITE_MIN_BYTES: u64 = 64 * 1024 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// Sync after every record. Nothing acknowledged is ever lost.
+    Always,
+    /// Sync once per second from the background thread
+    EverySec,
+    /// Leave flushing to the operating system
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => Err(format!("Unknown fsync policy: {}", s).into()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpLogConfig {
+    pub path: PathBuf,
+    pub fsync: FsyncPolicy,
+    pub rewrite_min_bytes: u64,
+}
+
+impl OpLogConfig {
+    /// Reads the log settings out of an actor binding. Returns `None` if the
+    /// binding does not ask for an operation log.
+    pub fn from_values(values: &HashMap<String, String>) -> Result<Option<Self>, Box<dyn Error>> {
+        let path = match values.get(OPLOG_PATH_KEY) {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(None),
+        };
+        let fsync = match values.get(OPLOG_FSYNC_KEY) {
+            Some(v) => v.parse()?,
+            None => FsyncPolicy::EverySec,
+        };
+        let rewrite_min_bytes = match values.get(OPLOG_REWRITE_MIN_BYTES_KEY) {
+            Some(v) => v.parse()?,
+            None => DEFAULT_REWRITE_MIN_BYTES,
+        };
+        Ok(Some(OpLogConfig {
+            path,
+            fsync,
+            rewrite_min_bytes,
+        }))
+    }
+}
+
+/// A write exactly as it was applied, after `ops::absolute`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Record {
+    pub seq: u64,
+    pub ns: Namespace,
+    pub op: String,
+    pub msg: Vec<u8>,
+    /// When the write was applied, in milliseconds since the unix epoch.
+    /// Replay judges expiry against this time rather than the wall clock.
+    /// Records written before it was added read as 0, so no key counts as
+    /// expired while they are replayed.
+    #[serde(default)]
+    pub at_ms: u64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+enum Entry {
+    /// The whole store as of the rewrite that produced this file
+    Base(Snapshot),
+    Op(Record),
+}
+
+pub struct OpLog {
+    config: OpLogConfig,
+    file: File,
+    size: u64,
+    /// Size right after the last rewrite, used to decide when to rewrite again
+    base_size: u64,
+    unsynced: bool,
+    /// Set once an append fails. The store then holds a write the log is
+    /// missing, so no more writes are accepted until the provider restarts.
+    failed: bool,
+}
+
+impl OpLog {
+    /// Opens the log at the configured path, replays it into `store` and
+    /// prepares it for appending. A missing file starts an empty log.
+    pub fn open(config: OpLogConfig, store: &mut KeyValueStore) -> Result<Self, Box<dyn Error>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&config.path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let size = if buf.is_empty() {
+            file.write_all(&header())?;
+            file.sync_all()?;
+            HEADER_LEN
+        } else {
+            let (entries, valid) = decode(&buf)?;
+            if valid < buf.len() as u64 {
+                warn!(
+                    "Discarding {} bytes of truncated or corrupt records at the end of {:?}",
+                    buf.len() as u64 - valid,
+                    config.path
+                );
+                file.set_len(valid)?;
+                file.sync_all()?;
+            }
+            replay(entries, store);
+            valid
+        };
+        file.seek(SeekFrom::Start(size))?;
+        Ok(OpLog {
+            config,
+            file,
+            size,
+            base_size: size,
+            unsynced: false,
+            failed: false,
+        })
+    }
+
+    pub fn config(&self) -> &OpLogConfig {
+        &self.config
+    }
+
+    /// Fails once an append has failed, so the caller can refuse a write
+    /// before applying it
+    pub fn check(&self) -> Result<(), KvError> {
+        if self.failed {
+            return Err(KvError::Internal(format!(
+                "Writing to {:?} failed earlier, no writes are accepted until restart",
+                self.config.path
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn append(&mut self, record: Record) -> Result<(), Box<dyn Error>> {
+        let result = self.write_record(record);
+        if let Err(ref e) = result {
+            error!("Appending to {:?} failed, refusing further writes: {}", self.config.path, e);
+            self.failed = true;
+        }
+        result
+    }
+
+    fn write_record(&mut self, record: Record) -> Result<(), Box<dyn Error>> {
+        let frame = frame(&Entry::Op(record))?;
+        self.file.write_all(&frame)?;
+        self.size += frame.len() as u64;
+        match self.config.fsync {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            FsyncPolicy::EverySec => self.unsynced = true,
+            FsyncPolicy::Never => {}
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Swaps the file for a read-only handle so every append fails, the way
+    /// it would on a full disk
+    #[cfg(test)]
+    pub fn break_appends(&mut self) -> Result<(), Box<dyn Error>> {
+        self.file = File::open(&self.config.path)?;
+        Ok(())
+    }
+
+    fn needs_rewrite(&self) -> bool {
+        self.size >= self.config.rewrite_min_bytes && self.size >= self.base_size * 2
+    }
+}
+
+/// Rewrites the log as one base record holding the current store, followed by
+/// the records appended while the base was being written. Writers are only
+/// blocked while the store is copied and while that short tail is moved over.
+pub fn rewrite(store: &RwLock<KeyValueStore>, oplog: &Mutex<Option<OpLog>>) -> Result<(), Box<dyn Error>> {
+    let (base, offset, path) = {
+        let store = store.read().unwrap();
+        let log = oplog.lock().unwrap();
+        match log.as_ref() {
+            Some(log) => (Snapshot::capture(&store)?, log.size, log.config.path.clone()),
+            None => return Ok(()),
+        }
+    };
+    let tmp = temp_path(&path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(&header())?;
+    file.write_all(&frame(&Entry::Base(base))?)?;
+    file.sync_all()?;
+
+    let mut log = oplog.lock().unwrap();
+    let log = match log.as_mut() {
+        Some(log) => log,
+        None => return Ok(()),
+    };
+    let mut tail = Vec::new();
+    let mut old = File::open(&path)?;
+    old.seek(SeekFrom::Start(offset))?;
+    old.read_to_end(&mut tail)?;
+    file.write_all(&tail)?;
+    file.sync_all()?;
+    replace(&tmp, &path)?;
+
+    log.file = OpenOptions::new().append(true).open(&path)?;
+    log.size = log.file.metadata()?.len();
+    log.base_size = log.size;
+    log.unsynced = false;
+    info!("Rewrote {:?}, now {} bytes", path, log.size);
+    Ok(())
+}
+
+/// Syncs the log once per second when the policy asks for it, and rewrites it
+/// once it has grown enough. The thread exits once the provider is dropped.
+pub fn spawn_oplog_worker(store: Weak<RwLock<KeyValueStore>>, oplog: Weak<Mutex<Option<OpLog>>>) {
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        let (store, oplog) = match (store.upgrade(), oplog.upgrade()) {
+            (Some(store), Some(oplog)) => (store, oplog),
+            _ => break,
+        };
+        let needs_rewrite = match oplog.lock().unwrap().as_mut() {
+            Some(log) => {
+                if let Err(e) = log.sync() {
+                    error!("Syncing {:?} failed: {}", log.config.path, e);
+                }
+                log.needs_rewrite()
+            }
+            None => false,
+        };
+        if needs_rewrite {
+            let started = Instant::now();
+            match rewrite(&store, &oplog) {
+                Ok(()) => trace!("Operation log rewrite took {:?}", started.elapsed()),
+                Err(e) => error!("Operation log rewrite failed: {}", e),
+            }
+        }
+    });
+}
+
+fn replay(entries: Vec<Entry>, store: &mut KeyValueStore) {
+    for entry in entries {
+        match entry {
+            Entry::Base(base) => {
+                let base_seq = base.seq();
+                if base_seq > store.seq() {
+                    *store = KeyValueStore::new();
+                    if let Err(e) = base.restore(store) {
+                        warn!("Restoring base record #{} failed: {}", base_seq, e);
+                    }
+                }
+            }
+            Entry::Op(record) => {
+                if record.seq <= store.seq() {
+                    continue;
+                }
+                let applied = store.at(record.at_ms, |store| {
+                    ops::apply(store, &record.ns, &record.op, &record.msg)
+                });
+                if let Err(e) = applied {
+                    warn!("Replaying {} #{} failed: {}", record.op, record.seq, e);
+                }
+                store.set_seq(record.seq);
+            }
+        }
+    }
+}
+
+fn header() -> Vec<u8> {
+    frame::header(MAGIC, VERSION)
+}
+
+/// Returns every intact entry and the length of the valid prefix of `buf`.
+/// Only a wrong header is an error; damage after it just ends the log early.
+fn decode(buf: &[u8]) -> Result<(Vec<Entry>, u64), Box<dyn Error>> {
+    frame::check_header(buf, MAGIC, VERSION, VERSION, "operation log")?;
+    let (entries, valid) = frame::decode(buf);
+    Ok((entries.into_iter().map(|(_, entry)| entry).collect(), valid))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rewrite, FsyncPolicy, OpLog, OpLogConfig, Record};
+    use crate::error::KvError;
+    use crate::kv::{now_millis, KeyValueStore, TTL_NO_EXPIRY};
+    use crate::namespace::Namespace;
+    use crate::ops;
+    use crate::protocol::{
+        self, Command, ExecRequest, ExpireAtRequest, PersistRequest, SetExAtRequest,
+        SetRandomRequest, WatchedKey,
+    };
+    use crate::snapshot::Snapshot;
+    use std::borrow::Cow;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::sync::{Mutex, RwLock};
+    use tea_codec::keyvalue::{self, AddRequest, ListPushRequest};
+    use wascc_codec::serialize;
+
+    fn config(name: &str) -> OpLogConfig {
+        let mut path = std::env::temp_dir();
+        path.push(format!("tea-kvp-{}-{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        OpLogConfig {
+            path,
+            fsync: FsyncPolicy::Always,
+            rewrite_min_bytes: 0,
+        }
+    }
+
+    fn add(seq: u64, value: i32) -> Record {
+        Record {
+            seq,
+            ns: Namespace::actor("MTEST"),
+            op: keyvalue::OP_ADD.to_string(),
+            msg: serialize(AddRequest {
+                key: "counter".to_string(),
+                value,
+            })
+            .unwrap(),
+            at_ms: now_millis(),
+        }
+    }
+
+    fn push(seq: u64, value: &str) -> Record {
+        Record {
+            seq,
+            ns: Namespace::actor("MTEST"),
+            op: keyvalue::OP_PUSH.to_string(),
+            msg: serialize(ListPushRequest {
+                key: "list".to_string(),
+                value: value.to_owned().into_bytes(),
+            })
+            .unwrap(),
+            at_ms: now_millis(),
+        }
+    }
+
+    fn counter(store: &mut KeyValueStore) -> i32 {
+        store.incr(&Namespace::actor("MTEST").key("counter"), 0).unwrap()
+    }
+
+    fn reopen(config: &OpLogConfig) -> KeyValueStore {
+        let mut store = KeyValueStore::new();
+        OpLog::open(config.clone(), &mut store).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_replay() {
+        let config = config("replay");
+        let mut store = KeyValueStore::new();
+        let mut log = OpLog::open(config.clone(), &mut store).unwrap();
+        log.append(add(1, 5)).unwrap();
+        log.append(add(2, 3)).unwrap();
+        drop(log);
+
+        let mut store = reopen(&config);
+        assert_eq!(2, store.seq());
+        assert_eq!(8, counter(&mut store));
+        fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_skips_records_in_snapshot() {
+        let config = config("skip");
+        let mut store = KeyValueStore::new();
+        let mut log = OpLog::open(config.clone(), &mut store).unwrap();
+        log.append(add(1, 5)).unwrap();
+        log.append(add(2, 3)).unwrap();
+        drop(log);
+
+        let mut store = KeyValueStore::new();
+        store.incr(&Namespace::actor("MTEST").key("counter"), 5).unwrap();
+        store.set_seq(1);
+        OpLog::open(config.clone(), &mut store).unwrap();
+        assert_eq!(8, counter(&mut store));
+        fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_judges_expiry_at_write_time() {
+        let config = config("expiry");
+        let ns = Namespace::actor("MTEST");
+        let now = now_millis();
+        let record = |seq, at_ms, op: &str, msg| Record {
+            seq,
+            ns: ns.clone(),
+            op: op.to_string(),
+            msg,
+            at_ms,
+        };
+        let mut store = KeyValueStore::new();
+        let mut log = OpLog::open(config.clone(), &mut store).unwrap();
+        // Persisted before its deadline, which has passed since
+        let set_ex_at = SetExAtRequest {
+            key: "session".to_string(),
+            value: b"token".to_vec(),
+            at_ms: now - 1_000,
+        };
+        log.append(record(1, now - 2_000, protocol::OP_SET_EX_AT, serialize(set_ex_at).unwrap())).unwrap();
+        let persist = PersistRequest {
+            key: "session".to_string(),
+        };
+        log.append(record(2, now - 1_500, protocol::OP_PERSIST, serialize(persist).unwrap())).unwrap();
+        // Had expired when it was added to again, so it started over
+        log.append(record(3, now - 2_000, keyvalue::OP_ADD, add(0, 5).msg)).unwrap();
+        let expire_at = ExpireAtRequest {
+            key: "counter".to_string(),
+            at_ms: now - 1_000,
+        };
+        log.append(record(4, now - 2_000, protocol::OP_EXPIRE_AT, serialize(expire_at).unwrap())).unwrap();
+        log.append(record(5, now - 500, keyvalue::OP_ADD, add(0, 1).msg)).unwrap();
+        drop(log);
+
+        let mut store = reopen(&config);
+        assert_eq!(b"token".to_vec(), store.get(&ns.key("session")).unwrap());
+        assert_eq!(TTL_NO_EXPIRY, store.ttl(&ns.key("session")).unwrap());
+        assert_eq!(1, counter(&mut store));
+        fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_tail() {
+        let config = config("truncated");
+        let mut store = KeyValueStore::new();
+        let mut log = OpLog::open(config.clone(), &mut store).unwrap();
+        log.append(add(1, 5)).unwrap();
+        log.append(add(2, 3)).unwrap();
+        drop(log);
+        let len = fs::metadata(&config.path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&config.path)
+            .unwrap()
+            .set_len(len - 2)
+            .unwrap();
+
+        let mut store = KeyValueStore::new();
+        let mut log = OpLog::open(config.clone(), &mut store).unwrap();
+        assert_eq!(1, store.seq());
+        assert_eq!(5, counter(&mut store));
+
+        log.append(add(2, 10)).unwrap();
+        drop(log);
+        assert_eq!(15, counter(&mut reopen(&config)));
+        fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_tail() {
+        let config = config("corrupt");
+        let mut store = KeyValueStore::new();
+        let mut log = OpLog::open(config.clone(), &mut store).unwrap();
+        log.append(add(1, 5)).unwrap();
+        drop(log);
+        let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+        file.write_all(&[0xff; 32]).unwrap();
+        drop(file);
+
+        assert_eq!(5, counter(&mut reopen(&config)));
+        fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let config = config("header");
+        fs::write(&config.path, b"not a log").unwrap();
+        assert!(OpLog::open(config.clone(), &mut KeyValueStore::new()).is_err());
+        fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_random_writes_replay_deterministically() {
+        let config = config("spop");
+        let ns = Namespace::actor("MTEST");
+        let mut store = KeyValueStore::new();
+        let mut log = OpLog::open(config.clone(), &mut store).unwrap();
+        for m in 0..20u8 {
+            store.sadd(&ns.key("set"), vec![m]).unwrap();
+        }
+        let members = store.smembers(ns.key("set")).unwrap();
+        let mut record = Record {
+            seq: 1,
+            ns: ns.clone(),
+            op: protocol::OP_SET_POP.to_string(),
+            msg: serialize(SetRandomRequest {
+                key: "set".to_string(),
+                count: 5,
+            })
+            .unwrap(),
+            at_ms: now_millis(),
+        };
+        let resp = ops::apply(&mut store, &ns, &record.op, &record.msg).unwrap();
+        let (op, msg) = ops::deterministic(&record.op, Cow::Borrowed(&record.msg), &resp).unwrap();
+        assert_eq!(protocol::OP_SET_REMOVE_MEMBERS, op);
+        record = Record {
+            op: op.to_string(),
+            msg: msg.into_owned(),
+            ..record
+        };
+        log.append(record).unwrap();
+        drop(log);
+
+        // Replay on top of the same members, as a snapshot would have restored them
+        let mut restored = KeyValueStore::new();
+        for member in members {
+            restored.sadd(&ns.key("set"), member).unwrap();
+        }
+        OpLog::open(config.clone(), &mut restored).unwrap();
+        let mut expected = store.smembers(ns.key("set")).unwrap();
+        let mut actual = restored.smembers(ns.key("set")).unwrap();
+        expected.sort();
+        actual.sort();
+        assert_eq!(15, actual.len());
+        assert_eq!(expected, actual);
+        fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let config = config("rewrite");
+        let mut store = KeyValueStore::new();
+        let mut log = OpLog::open(config.clone(), &mut store).unwrap();
+        for seq in 1..=100 {
+            let record = push(seq, "event");
+            crate::ops::apply(&mut store, &record.ns, &record.op, &record.msg).unwrap();
+            store.set_seq(seq);
+            log.append(record).unwrap();
+        }
+        let before = fs::metadata(&config.path).unwrap().len();
+        assert!(log.needs_rewrite());
+
+        let store = RwLock::new(store);
+        let oplog = Mutex::new(Some(log));
+        rewrite(&store, &oplog).unwrap();
+        assert!(fs::metadata(&config.path).unwrap().len() < before);
+
+        oplog.lock().unwrap().as_mut().unwrap().append(add(101, 7)).unwrap();
+        drop(oplog);
+
+        let mut restored = reopen(&config);
+        assert_eq!(101, restored.seq());
+        assert_eq!(7, counter(&mut restored));
+        let list = Namespace::actor("MTEST").key("list");
+        assert_eq!(100, restored.lrange(&list, 0, 1000).unwrap().len());
+        fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_base_replaces_older_snapshot() {
+        let config = config("base");
+        let mut store = KeyValueStore::new();
+        store.set("stale", vec![1]).unwrap();
+        let snapshot = Snapshot::capture(&store).unwrap();
+
+        let mut store = KeyValueStore::new();
+        let log = OpLog::open(config.clone(), &mut store).unwrap();
+        let store = RwLock::new(store);
+        let oplog = Mutex::new(Some(log));
+        oplog.lock().unwrap().as_mut().unwrap().append(add(1, 1)).unwrap();
+        store.write().unwrap().set_seq(1);
+        rewrite(&store, &oplog).unwrap();
+        drop(oplog);
+
+        let mut restored = KeyValueStore::new();
+        snapshot.restore(&mut restored).unwrap();
+        OpLog::open(config.clone(), &mut restored).unwrap();
+        assert_eq!(false, restored.exists("stale").unwrap());
+        fs::remove_file(&config.path).unwrap();
+    }
+
+    #[test]
+    fn test_transactions_replay_deterministically() {
+        let config = config("exec");
+        let ns = Namespace::actor("MTEST");
+        let mut store = KeyValueStore::new();
+        let mut log = OpLog::open(config.clone(), &mut store).unwrap();
+        for m in 0..20u8 {
+            store.sadd(&ns.key("set"), vec![m]).unwrap();
+        }
+        let members = store.smembers(ns.key("set")).unwrap();
+        let exec = |version: u64, commands: Vec<(&str, Vec<u8>)>| {
+            serialize(ExecRequest {
+                watch: vec![WatchedKey {
+                    key: "set".to_string(),
+                    version,
+                }],
+                commands: commands
+                    .into_iter()
+                    .map(|(op, msg)| Command {
+                        op: op.to_string(),
+                        msg,
+                    })
+                    .collect(),
+            })
+            .unwrap()
+        };
+        let pop = serialize(SetRandomRequest {
+            key: "set".to_string(),
+            count: 5,
+        })
+        .unwrap();
+        let version = store.version(&ns.key("set")).unwrap();
+        let msg = exec(version, vec![
+            (keyvalue::OP_ADD, add(0, 5).msg),
+            (protocol::OP_SET_POP, pop),
+        ]);
+        let resp = ops::apply(&mut store, &ns, protocol::OP_EXEC, &msg).unwrap();
+        let (op, msg) = ops::deterministic(protocol::OP_EXEC, Cow::Borrowed(&msg), &resp).unwrap();
+        log.append(Record {
+            seq: 1,
+            ns: ns.clone(),
+            op: op.to_string(),
+            msg: msg.into_owned(),
+            at_ms: now_millis(),
+        })
+        .unwrap();
+
+        // Failing transactions leave nothing behind to log
+        let version = store.version(&ns.key("set")).unwrap();
+        let msg = exec(version, vec![
+            (keyvalue::OP_ADD, add(0, 1).msg),
+            (protocol::OP_SET_POP, add(0, 1).msg),
+        ]);
+        assert!(ops::apply(&mut store, &ns, protocol::OP_EXEC, &msg).is_err());
+        let msg = exec(version - 1, vec![(keyvalue::OP_ADD, add(0, 1).msg)]);
+        let e = KvError::from(ops::apply(&mut store, &ns, protocol::OP_EXEC, &msg).unwrap_err());
+        assert_eq!(protocol::ERR_CONFLICT, e.code());
+        assert_eq!(5, counter(&mut store));
+        drop(log);
+
+        let mut restored = KeyValueStore::new();
+        for member in members {
+            restored.sadd(&ns.key("set"), member).unwrap();
+        }
+        OpLog::open(config.clone(), &mut restored).unwrap();
+        let mut expected = store.smembers(ns.key("set")).unwrap();
+        let mut actual = restored.smembers(ns.key("set")).unwrap();
+        expected.sort();
+        actual.sort();
+        assert_eq!(15, actual.len());
+        assert_eq!(expected, actual);
+        assert_eq!(5, counter(&mut restored));
+        fs::remove_file(&config.path).unwrap();
+    }
+}
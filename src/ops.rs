@@ -0,0 +1,1515 @@
+//! Request handlers for every `tea:keyvalue` operation. Handlers work on an
+//! already locked store so the provider can decide how long a lock is held,
+//! and so the operation log can replay writes through the exact same code.
+
+use crate::error::KvError;
+use crate::kv::{now_millis, ItemType, KeyValueStore, Score};
+use crate::namespace::Namespace;
+use crate::protocol;
+use crate::protocol::*;
+use crate::sorted_vec::F64;
+use std::borrow::Cow;
+use std::error::Error;
+use std::ops::Bound;
+use tea_codec::keyvalue;
+use tea_codec::keyvalue::*;
+use serde::de::DeserializeOwned;
+use wascc_codec::{deserialize, serialize};
+
+/// Operations that modify the store. They run under the write lock and are
+/// recorded in the operation log.
+pub fn is_write(op: &str) -> bool {
+    match op {
+        keyvalue::OP_ADD
+        | keyvalue::OP_DEL
+        | keyvalue::OP_CLEAR
+        | keyvalue::OP_PUSH
+        | keyvalue::OP_SET
+        | keyvalue::OP_LIST_DEL
+        | keyvalue::OP_SET_ADD
+        | keyvalue::OP_SET_REMOVE
+        | keyvalue::OP_KEYVEC_INSERT
+        | keyvalue::OP_KEYVEC_TAILOFF
+        | keyvalue::OP_KEYVEC_REMOVE_ITEM
+        | protocol::OP_SET_EX
+        | protocol::OP_SET_EX_AT
+        | protocol::OP_EXPIRE
+        | protocol::OP_EXPIRE_AT
+        | protocol::OP_PERSIST
+        | protocol::OP_LPUSH
+        | protocol::OP_RPUSH
+        | protocol::OP_LPOP
+        | protocol::OP_RPOP
+        | protocol::OP_LSET
+        | protocol::OP_LINSERT
+        | protocol::OP_LTRIM
+        | protocol::OP_LMOVE
+        | protocol::OP_SET_POP
+        | protocol::OP_SET_MOVE
+        | protocol::OP_SET_REMOVE_MEMBERS
+        | protocol::OP_SET_UNION_STORE
+        | protocol::OP_SET_INTER_STORE
+        | protocol::OP_SET_DIFF_STORE
+        | protocol::OP_ZADD
+        | protocol::OP_ZINCRBY
+        | protocol::OP_ZREM
+        | protocol::OP_KEYVEC_POP_MIN
+        | protocol::OP_KEYVEC_POP_MAX
+        | protocol::OP_KEYVEC_HEADOFF
+        | protocol::OP_KEYVEC_REMOVE_RANGE_BY_SCORE
+        | protocol::OP_KEYVEC_SCORED_INSERT
+        | protocol::OP_KEYVEC_SCORED_TAILOFF
+        | protocol::OP_KEYVEC_SCORED_REMOVE_ITEM
+        | protocol::OP_HSET
+        | protocol::OP_HSETNX
+        | protocol::OP_HDEL
+        | protocol::OP_HINCRBY
+        | protocol::OP_INCR_BY
+        | protocol::OP_DECR_BY
+        | protocol::OP_INCR_BY_FLOAT
+        | protocol::OP_EXEC
+        | protocol::OP_SET_IF
+        | protocol::OP_SET_IF_AT
+        | protocol::OP_GETSET
+        | protocol::OP_COMPARE_AND_SWAP
+        | protocol::OP_MSET
+        | protocol::OP_MSETNX
+        | protocol::OP_MDEL => true,
+        _ => false,
+    }
+}
+
+/// Rejects requests that decode but can never succeed, so they fail before
+/// the write lock is taken
+pub fn validate(op: &str, msg: &[u8]) -> Result<(), KvError> {
+    if op == protocol::OP_EXEC {
+        let req: ExecRequest = decode(msg)?;
+        if req.commands.iter().any(|c| c.op == protocol::OP_EXEC) {
+            return Err(nested_exec());
+        }
+    }
+    Ok(())
+}
+
+fn nested_exec() -> KvError {
+    KvError::InvalidRequest("Exec can't contain another Exec".into())
+}
+
+/// Turns the time to live of a `SetIf` into the deadline of a `SetIfAt`
+fn set_if_deadline(req: SetIfRequest) -> SetIfAtRequest {
+    SetIfAtRequest {
+        key: req.key,
+        value: req.value,
+        condition: req.condition,
+        at_ms: req.ttl_ms.map(|ttl_ms| now_millis().saturating_add(ttl_ms)),
+    }
+}
+
+/// Rewrites operations that are relative to the current time into their
+/// absolute form, so replaying the operation log later yields the same deadlines.
+pub fn absolute<'a>(
+    op: &'a str,
+    msg: &'a [u8],
+) -> Result<(&'a str, Cow<'a, [u8]>), Box<dyn Error>> {
+    Ok(match op {
+        protocol::OP_EXPIRE => {
+            let req: ExpireRequest = decode(msg)?;
+            let req = ExpireAtRequest {
+                key: req.key,
+                at_ms: now_millis().saturating_add(req.ttl_ms),
+            };
+            (protocol::OP_EXPIRE_AT, Cow::Owned(serialize(req)?))
+        }
+        protocol::OP_SET_EX => {
+            let req: SetExRequest = decode(msg)?;
+            let req = SetExAtRequest {
+                key: req.key,
+                value: req.value,
+                at_ms: now_millis().saturating_add(req.ttl_ms),
+            };
+            (protocol::OP_SET_EX_AT, Cow::Owned(serialize(req)?))
+        }
+        protocol::OP_SET_IF => {
+            let req = set_if_deadline(decode(msg)?);
+            (protocol::OP_SET_IF_AT, Cow::Owned(serialize(req)?))
+        }
+        protocol::OP_EXEC => {
+            let req: ExecRequest = decode(msg)?;
+            let commands = req
+                .commands
+                .iter()
+                .map(|c| {
+                    let (op, msg) = absolute(&c.op, &c.msg)?;
+                    Ok(Command {
+                        op: op.to_string(),
+                        msg: msg.into_owned(),
+                    })
+                })
+                .collect::<Result<_, Box<dyn Error>>>()?;
+            (
+                op,
+                Cow::Owned(serialize(ExecRequest {
+                    watch: req.watch,
+                    commands,
+                })?),
+            )
+        }
+        _ => (op, Cow::Borrowed(msg)),
+    })
+}
+
+fn decode<T: DeserializeOwned>(msg: &[u8]) -> Result<T, KvError> {
+    deserialize(msg).map_err(|e| KvError::DecodeError(e.to_string()))
+}
+
+/// Rewrites a write that made random choices into one that repeats exactly
+/// what it did, given the response it produced, so replaying the operation
+/// log yields the same store.
+pub fn deterministic<'a>(
+    op: &'a str,
+    msg: Cow<'a, [u8]>,
+    resp: &[u8],
+) -> Result<(&'a str, Cow<'a, [u8]>), Box<dyn Error>> {
+    Ok(match op {
+        protocol::OP_SET_POP => {
+            let req: SetRandomRequest = decode(&msg)?;
+            let resp: SetQueryResponse = decode(resp)?;
+            let req = SetMembersRequest {
+                key: req.key,
+                members: resp.values,
+            };
+            (protocol::OP_SET_REMOVE_MEMBERS, Cow::Owned(serialize(req)?))
+        }
+        protocol::OP_EXEC => {
+            let req: ExecRequest = decode(&msg)?;
+            let resp: ExecResponse = decode(resp)?;
+            let commands = req
+                .commands
+                .iter()
+                .zip(resp.results.iter())
+                .map(|(c, resp)| {
+                    let (op, msg) = deterministic(&c.op, Cow::Borrowed(&c.msg), resp)?;
+                    Ok(Command {
+                        op: op.to_string(),
+                        msg: msg.into_owned(),
+                    })
+                })
+                .collect::<Result<_, Box<dyn Error>>>()?;
+            // The versions were checked when the batch ran and will differ on replay
+            let req = ExecRequest {
+                watch: vec![],
+                commands,
+            };
+            (op, Cow::Owned(serialize(req)?))
+        }
+        _ => (op, msg),
+    })
+}
+
+pub fn apply(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    op: &str,
+    msg: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    match op {
+        keyvalue::OP_ADD => add(store, ns, decode(msg)?),
+        keyvalue::OP_DEL => del(store, ns, decode(msg)?),
+        keyvalue::OP_CLEAR => list_clear(store, ns, decode(msg)?),
+        keyvalue::OP_PUSH => list_push(store, ns, decode(msg)?),
+        keyvalue::OP_SET => set(store, ns, decode(msg)?),
+        keyvalue::OP_LIST_DEL => list_del_item(store, ns, decode(msg)?),
+        keyvalue::OP_SET_ADD => set_add(store, ns, decode(msg)?),
+        keyvalue::OP_SET_REMOVE => set_remove(store, ns, decode(msg)?),
+        keyvalue::OP_KEYVEC_INSERT => sv_insert(store, ns, decode(msg)?),
+        keyvalue::OP_KEYVEC_TAILOFF => sv_tail_off(store, ns, decode(msg)?),
+        keyvalue::OP_KEYVEC_REMOVE_ITEM => sv_remove_item(store, ns, decode(msg)?),
+        protocol::OP_SET_EX => set_ex(store, ns, decode(msg)?),
+        protocol::OP_SET_EX_AT => set_ex_at(store, ns, decode(msg)?),
+        protocol::OP_EXPIRE => expire(store, ns, decode(msg)?),
+        protocol::OP_EXPIRE_AT => expire_at(store, ns, decode(msg)?),
+        protocol::OP_PERSIST => persist(store, ns, decode(msg)?),
+        protocol::OP_LPUSH => lpush(store, ns, decode(msg)?),
+        protocol::OP_RPUSH => rpush(store, ns, decode(msg)?),
+        protocol::OP_LPOP => lpop(store, ns, decode(msg)?),
+        protocol::OP_RPOP => rpop(store, ns, decode(msg)?),
+        protocol::OP_LSET => lset(store, ns, decode(msg)?),
+        protocol::OP_LINSERT => linsert(store, ns, decode(msg)?),
+        protocol::OP_LTRIM => ltrim(store, ns, decode(msg)?),
+        protocol::OP_LMOVE => lmove(store, ns, decode(msg)?),
+        protocol::OP_SET_POP => set_pop(store, ns, decode(msg)?),
+        protocol::OP_SET_MOVE => set_move(store, ns, decode(msg)?),
+        protocol::OP_SET_REMOVE_MEMBERS => set_remove_members(store, ns, decode(msg)?),
+        protocol::OP_SET_UNION_STORE => set_union_store(store, ns, decode(msg)?),
+        protocol::OP_SET_INTER_STORE => set_inter_store(store, ns, decode(msg)?),
+        protocol::OP_SET_DIFF_STORE => set_diff_store(store, ns, decode(msg)?),
+        protocol::OP_ZADD => zadd(store, ns, decode(msg)?),
+        protocol::OP_ZINCRBY => zincrby(store, ns, decode(msg)?),
+        protocol::OP_ZREM => zrem(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_POP_MIN => sv_pop_min(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_POP_MAX => sv_pop_max(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_HEADOFF => sv_head_off(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_REMOVE_RANGE_BY_SCORE => sv_remove_range_by_score(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_SCORED_INSERT => sv_scored_insert(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_SCORED_TAILOFF => sv_scored_tail_off(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_SCORED_REMOVE_ITEM => sv_scored_remove_item(store, ns, decode(msg)?),
+        protocol::OP_HSET => hset(store, ns, decode(msg)?),
+        protocol::OP_HSETNX => hsetnx(store, ns, decode(msg)?),
+        protocol::OP_HDEL => hdel(store, ns, decode(msg)?),
+        protocol::OP_HINCRBY => hincrby(store, ns, decode(msg)?),
+        protocol::OP_INCR_BY => incr_by(store, ns, decode(msg)?),
+        protocol::OP_DECR_BY => decr_by(store, ns, decode(msg)?),
+        protocol::OP_INCR_BY_FLOAT => incr_by_float(store, ns, decode(msg)?),
+        protocol::OP_EXEC => exec(store, ns, decode(msg)?),
+        protocol::OP_SET_IF => set_if(store, ns, decode(msg)?),
+        protocol::OP_SET_IF_AT => set_if_at(store, ns, decode(msg)?),
+        protocol::OP_GETSET => getset(store, ns, decode(msg)?),
+        protocol::OP_COMPARE_AND_SWAP => compare_and_swap(store, ns, decode(msg)?),
+        protocol::OP_MSET => mset(store, ns, decode(msg)?),
+        protocol::OP_MSETNX => msetnx(store, ns, decode(msg)?),
+        protocol::OP_MDEL => mdel(store, ns, decode(msg)?),
+        _ => query(store, ns, op, msg),
+    }
+}
+
+pub fn query(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    op: &str,
+    msg: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    match op {
+        keyvalue::OP_GET => get(store, ns, decode(msg)?),
+        keyvalue::OP_RANGE => list_range(store, ns, decode(msg)?),
+        keyvalue::OP_SET_UNION => set_union(store, ns, decode(msg)?),
+        keyvalue::OP_SET_INTERSECT => set_intersect(store, ns, decode(msg)?),
+        keyvalue::OP_SET_QUERY => set_query(store, ns, decode(msg)?),
+        keyvalue::OP_KEY_EXISTS => exists(store, ns, decode(msg)?),
+        keyvalue::OP_KEYVEC_GET => sv_get(store, ns, decode(msg)?),
+        protocol::OP_TTL => ttl(store, ns, decode(msg)?),
+        protocol::OP_LINDEX => lindex(store, ns, decode(msg)?),
+        protocol::OP_LLEN => llen(store, ns, decode(msg)?),
+        protocol::OP_SET_IS_MEMBER => set_is_member(store, ns, decode(msg)?),
+        protocol::OP_SET_ARE_MEMBERS => set_are_members(store, ns, decode(msg)?),
+        protocol::OP_SET_CARD => set_card(store, ns, decode(msg)?),
+        protocol::OP_SET_DIFF => set_diff(store, ns, decode(msg)?),
+        protocol::OP_SET_RANDOM => set_random(store, ns, decode(msg)?),
+        protocol::OP_ZSCORE => zscore(store, ns, decode(msg)?),
+        protocol::OP_ZRANK => zrank(store, ns, decode(msg)?),
+        protocol::OP_ZREVRANK => zrevrank(store, ns, decode(msg)?),
+        protocol::OP_ZCARD => zcard(store, ns, decode(msg)?),
+        protocol::OP_ZRANGE => zrange(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_RANGE_BY_SCORE => sv_range_by_score(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_RANGE_BY_RANK => sv_range_by_rank(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_COUNT => sv_count(store, ns, decode(msg)?),
+        protocol::OP_KEYVEC_SCORED_GET => sv_scored_get(store, ns, decode(msg)?),
+        protocol::OP_HGET => hget(store, ns, decode(msg)?),
+        protocol::OP_HMGET => hmget(store, ns, decode(msg)?),
+        protocol::OP_HEXISTS => hexists(store, ns, decode(msg)?),
+        protocol::OP_HLEN => hlen(store, ns, decode(msg)?),
+        protocol::OP_HKEYS => hkeys(store, ns, decode(msg)?),
+        protocol::OP_HVALS => hvals(store, ns, decode(msg)?),
+        protocol::OP_HGETALL => hgetall(store, ns, decode(msg)?),
+        protocol::OP_VERSIONS => versions(store, ns, decode(msg)?),
+        protocol::OP_GET_VERSIONED => get_versioned(store, ns, decode(msg)?),
+        protocol::OP_MGET => mget(store, ns, decode(msg)?),
+        protocol::OP_SCAN => scan(store, ns, decode(msg)?),
+        _ => Err(KvError::UnknownOp(op.to_string()).into()),
+    }
+}
+
+fn exec(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ExecRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    for watched in req.watch.iter() {
+        let version = store.version(&ns.key(&watched.key))?;
+        if version != watched.version {
+            return Err(KvError::Conflict(format!(
+                "{} is at version {}, not {}",
+                watched.key, version, watched.version
+            ))
+            .into());
+        }
+    }
+    let results = store.transaction(|store| {
+        req.commands
+            .iter()
+            .map(|c| {
+                if c.op == protocol::OP_EXEC {
+                    return Err(nested_exec());
+                }
+                Ok(apply(store, ns, &c.op, &c.msg)?)
+            })
+            .collect()
+    })?;
+    Ok(serialize(ExecResponse { results })?)
+}
+
+fn add(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: AddRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let res: i32 = store.incr(&ns.key(&req.key), req.value)?;
+    let resp = AddResponse { value: res };
+
+    Ok(serialize(resp)?)
+}
+
+fn incr_by(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: CounterRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.incr_by(&ns.key(&req.key), req.delta)?;
+    Ok(serialize(CounterResponse { value: result })?)
+}
+
+fn decr_by(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: CounterRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.decr_by(&ns.key(&req.key), req.delta)?;
+    Ok(serialize(CounterResponse { value: result })?)
+}
+
+fn incr_by_float(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: FloatCounterRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.incr_by_float(&ns.key(&req.key), req.delta)?;
+    Ok(serialize(FloatCounterResponse { value: result })?)
+}
+
+fn del(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: DelRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    store.del(&ns.key(&req.key))?;
+    let resp = DelResponse { key: req.key };
+
+    Ok(serialize(resp)?)
+}
+
+fn get(store: &KeyValueStore, ns: &Namespace, req: GetRequest) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    if !store.exists(&key)? {
+        Ok(serialize(GetResponse {
+            value: vec![],
+            exists: false,
+        })?)
+    } else {
+        Ok(serialize(GetResponse {
+            value: store.get(&key)?,
+            exists: true,
+        })?)
+    }
+}
+
+fn set_if(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetIfRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    set_if_at(store, ns, set_if_deadline(req))
+}
+
+fn set_if_at(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetIfAtRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    let success = store.set_if(&key, req.value, req.condition)?;
+    if let (true, Some(at_ms)) = (success, req.at_ms) {
+        store.expire_at(&key, at_ms)?;
+    }
+    Ok(serialize(SetIfResponse { success })?)
+}
+
+fn getset(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: GetSetRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.getset(&ns.key(&req.key), req.value)?;
+    Ok(serialize(GetResponse {
+        exists: result.is_some(),
+        value: result.unwrap_or_default(),
+    })?)
+}
+
+fn compare_and_swap(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: CompareAndSwapRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let success = store.compare_and_swap(&ns.key(&req.key), &req.expected, req.value)?;
+    Ok(serialize(SetIfResponse { success })?)
+}
+
+fn mget(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: MKeysRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let keys: Vec<String> = req.keys.iter().map(|key| ns.key(key)).collect();
+    let result = store.mget(&keys)?;
+    Ok(serialize(MGetResponse {
+        values: result
+            .into_iter()
+            .map(|value| MGetValue {
+                exists: value.is_some(),
+                value: value.unwrap_or_default(),
+            })
+            .collect(),
+    })?)
+}
+
+fn mset(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: MSetRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let entries = req.entries.into_iter().map(|e| (ns.key(&e.key), e.value)).collect();
+    let existed = store.mset(entries)?;
+    Ok(serialize(MExistedResponse { existed })?)
+}
+
+fn msetnx(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: MSetRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let entries = req.entries.into_iter().map(|e| (ns.key(&e.key), e.value)).collect();
+    let existed = store.msetnx(entries)?;
+    Ok(serialize(MSetNxResponse {
+        success: !existed.contains(&true),
+        existed,
+    })?)
+}
+
+fn mdel(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: MKeysRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let keys: Vec<String> = req.keys.iter().map(|key| ns.key(key)).collect();
+    let existed = store.mdel(&keys)?;
+    Ok(serialize(MExistedResponse { existed })?)
+}
+
+fn get_versioned(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: GetRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    let version = store.version(&key)?;
+    Ok(serialize(VersionedGetResponse {
+        value: if version == 0 { vec![] } else { store.get(&key)? },
+        exists: version != 0,
+        version,
+    })?)
+}
+
+fn versions(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: VersionsRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let versions = req
+        .keys
+        .iter()
+        .map(|key| store.version(&ns.key(key)))
+        .collect::<Result<_, _>>()?;
+    Ok(serialize(VersionsResponse { versions })?)
+}
+
+/// Keys a `Scan` page looks at when the request leaves `count` at 0
+const DEFAULT_SCAN_COUNT: usize = 100;
+/// Keeps a single page from holding the read lock for long
+const MAX_SCAN_COUNT: usize = 10_000;
+
+fn scan(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: ScanRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let count = match req.count {
+        0 => DEFAULT_SCAN_COUNT,
+        n => (n as usize).min(MAX_SCAN_COUNT),
+    };
+    let cursor = req.cursor.map(|key| ns.key(&key));
+    let (keys, cursor) = store.scan(
+        &ns.key(""),
+        cursor.as_deref(),
+        count,
+        req.pattern.as_deref(),
+        req.item_type,
+    )?;
+    Ok(serialize(ScanResponse {
+        keys: keys
+            .iter()
+            .filter_map(|key| ns.strip(key))
+            .map(String::from)
+            .collect(),
+        cursor: cursor.as_deref().and_then(|key| ns.strip(key)).map(String::from),
+    })?)
+}
+
+fn set_ex(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetExRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    store.set_ex(&ns.key(&req.key), req.value.clone(), req.ttl_ms)?;
+    Ok(serialize(SetResponse { value: req.value })?)
+}
+
+fn set_ex_at(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetExAtRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    store.set(&key, req.value.clone())?;
+    store.expire_at(&key, req.at_ms)?;
+    Ok(serialize(SetResponse { value: req.value })?)
+}
+
+fn expire(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ExpireRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: bool = store.expire(&ns.key(&req.key), req.ttl_ms)?;
+    Ok(serialize(ExpireResponse { success: result })?)
+}
+
+fn expire_at(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ExpireAtRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: bool = store.expire_at(&ns.key(&req.key), req.at_ms)?;
+    Ok(serialize(ExpireResponse { success: result })?)
+}
+
+fn persist(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: PersistRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: bool = store.persist(&ns.key(&req.key))?;
+    Ok(serialize(ExpireResponse { success: result })?)
+}
+
+fn ttl(store: &KeyValueStore, ns: &Namespace, req: TtlRequest) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: i64 = store.ttl(&ns.key(&req.key))?;
+    Ok(serialize(TtlResponse { ttl_ms: result })?)
+}
+
+fn list_clear(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ListClearRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    del(store, ns, DelRequest { key: req.key })
+}
+
+fn list_range(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: ListRangeRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<Vec<u8>> = store.lrange(&ns.key(&req.key), req.start as _, req.stop as _)?;
+    Ok(serialize(ListRangeResponse { values: result })?)
+}
+
+fn list_push(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ListPushRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.rpush(&ns.key(&req.key), vec![req.value])?;
+    Ok(serialize(ListResponse {
+        new_count: result as i32,
+    })?)
+}
+
+fn lpush(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: PushRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.lpush(&ns.key(&req.key), req.values)?;
+    Ok(serialize(ListLenResponse { len: result as u64 })?)
+}
+
+fn rpush(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: PushRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.rpush(&ns.key(&req.key), req.values)?;
+    Ok(serialize(ListLenResponse { len: result as u64 })?)
+}
+
+fn lpop(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: PopRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<Vec<u8>> = store.lpop(&ns.key(&req.key), req.count as usize)?;
+    Ok(serialize(ListValuesResponse { values: result })?)
+}
+
+fn rpop(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: PopRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<Vec<u8>> = store.rpop(&ns.key(&req.key), req.count as usize)?;
+    Ok(serialize(ListValuesResponse { values: result })?)
+}
+
+fn lindex(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: ListIndexRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.lindex(&ns.key(&req.key), req.index)?;
+    Ok(serialize(ListValueResponse {
+        exists: result.is_some(),
+        value: result.unwrap_or_default(),
+    })?)
+}
+
+fn lset(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ListSetRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    store.lset(&ns.key(&req.key), req.index, req.value.clone())?;
+    Ok(serialize(SetResponse { value: req.value })?)
+}
+
+fn linsert(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ListInsertRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: i64 = store.linsert(&ns.key(&req.key), &req.pivot, req.value, req.before)?;
+    Ok(serialize(ListInsertResponse { len: result })?)
+}
+
+fn ltrim(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ListTrimRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.ltrim(&ns.key(&req.key), req.start, req.stop)?;
+    Ok(serialize(ListLenResponse { len: result as u64 })?)
+}
+
+fn llen(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: ListLenRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.llen(&ns.key(&req.key))?;
+    Ok(serialize(ListLenResponse { len: result as u64 })?)
+}
+
+fn lmove(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ListMoveRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.lmove(&ns.key(&req.source), &ns.key(&req.destination), req.from, req.to)?;
+    Ok(serialize(ListValueResponse {
+        exists: result.is_some(),
+        value: result.unwrap_or_default(),
+    })?)
+}
+
+fn set(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    store.set(&ns.key(&req.key), req.value.clone())?;
+    Ok(serialize(SetResponse { value: req.value })?)
+}
+
+fn list_del_item(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ListDelItemRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: i32 = store.lrem(&ns.key(&req.key), req.value)?;
+    Ok(serialize(ListResponse { new_count: result })?)
+}
+
+fn set_add(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetAddRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: i32 = store.sadd(&ns.key(&req.key), req.value)?;
+    Ok(serialize(SetOperationResponse { new_count: result })?)
+}
+
+fn set_remove(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetRemoveRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: i32 = store.srem(&ns.key(&req.key), req.value)?;
+    Ok(serialize(SetOperationResponse { new_count: result })?)
+}
+
+fn set_union(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: SetUnionRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<Vec<u8>> = store.sunion(ns.keys(req.keys))?;
+    Ok(serialize(SetQueryResponse { values: result })?)
+}
+
+fn set_intersect(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: SetIntersectionRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<Vec<u8>> = store.sinter(ns.keys(req.keys))?;
+    Ok(serialize(SetQueryResponse { values: result })?)
+}
+
+fn set_query(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: SetQueryRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<Vec<u8>> = store.smembers(ns.key(&req.key))?;
+    Ok(serialize(SetQueryResponse { values: result })?)
+}
+
+fn exists(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: KeyExistsQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: bool = store.exists(&ns.key(&req.key))?;
+    Ok(serialize(GetResponse {
+        value: vec![],
+        exists: result,
+    })?)
+}
+
+fn sv_insert(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecInsertQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: bool = store.sv_insert::<i32>(&ns.key(&req.key), &req.value, req.overwrite)?;
+    Ok(serialize(KeyVecInsertResponse { success: result })?)
+}
+
+fn sv_get(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecGetQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<(i32, Vec<u8>)> = store.sv_into_vec(&ns.key(&req.key))?;
+    Ok(serialize(KeyVecGetResponse { values: result })?)
+}
+
+fn sv_tail_off(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecTailOffQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: usize = store.sv_tail_off::<i32>(&ns.key(&req.key), req.remain)?;
+    Ok(serialize(KeyVecTailOffResponse { len: result })?)
+}
+
+fn sv_remove_item(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecRemoveItemQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: bool = store.sv_remove_item::<i32>(&ns.key(&req.key), req.value)?;
+    Ok(serialize(KeyVecRemoveItemResponse { success: result })?)
+}
+
+/// Converts sorted vec scores to and from the `ScoreValue` they travel as
+trait WireScore: Score {
+    fn from_wire(score: ScoreValue) -> Option<Self>;
+    fn to_wire(self) -> ScoreValue;
+}
+
+impl WireScore for i32 {
+    fn from_wire(score: ScoreValue) -> Option<Self> {
+        match score {
+            ScoreValue::I32(score) => Some(score),
+            _ => None,
+        }
+    }
+
+    fn to_wire(self) -> ScoreValue {
+        ScoreValue::I32(self)
+    }
+}
+
+impl WireScore for i64 {
+    fn from_wire(score: ScoreValue) -> Option<Self> {
+        match score {
+            ScoreValue::I64(score) => Some(score),
+            _ => None,
+        }
+    }
+
+    fn to_wire(self) -> ScoreValue {
+        ScoreValue::I64(self)
+    }
+}
+
+impl WireScore for u64 {
+    fn from_wire(score: ScoreValue) -> Option<Self> {
+        match score {
+            ScoreValue::U64(score) => Some(score),
+            _ => None,
+        }
+    }
+
+    fn to_wire(self) -> ScoreValue {
+        ScoreValue::U64(self)
+    }
+}
+
+impl WireScore for F64 {
+    fn from_wire(score: ScoreValue) -> Option<Self> {
+        match score {
+            ScoreValue::F64(score) => Some(F64(score)),
+            _ => None,
+        }
+    }
+
+    fn to_wire(self) -> ScoreValue {
+        ScoreValue::F64(self.0)
+    }
+}
+
+/// The item type of a sorted vec keyed by scores like `score`
+fn score_type(score: ScoreValue) -> ItemType {
+    match score {
+        ScoreValue::I32(_) => i32::ITEM_TYPE,
+        ScoreValue::I64(_) => i64::ITEM_TYPE,
+        ScoreValue::U64(_) => u64::ITEM_TYPE,
+        ScoreValue::F64(_) => F64::ITEM_TYPE,
+    }
+}
+
+fn from_wire<S: WireScore>(score: ScoreValue) -> Result<S, KvError> {
+    S::from_wire(score).ok_or_else(|| KvError::WrongType {
+        expected: score_type(score),
+        actual: S::ITEM_TYPE,
+    })
+}
+
+fn bound<S: WireScore>(bound: ScoreBound) -> Result<Bound<S>, KvError> {
+    Ok(match bound {
+        ScoreBound::Inclusive(score) => Bound::Included(from_wire(score)?),
+        ScoreBound::Exclusive(score) => Bound::Excluded(from_wire(score)?),
+        ScoreBound::Unbounded => Bound::Unbounded,
+    })
+}
+
+fn scored_values<S: WireScore>(entries: Vec<(S, Vec<u8>)>) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(serialize(ScoredValuesResponse {
+        values: entries
+            .into_iter()
+            .map(|(score, value)| ScoredValue {
+                score: score.to_wire(),
+                value,
+            })
+            .collect(),
+    })?)
+}
+
+/// The sorted vec type the scores bounding a range belong to, `None` if both
+/// ends are unbounded
+fn range_type(min: ScoreBound, max: ScoreBound) -> Option<ItemType> {
+    [min, max].iter().find_map(|bound| match bound {
+        ScoreBound::Inclusive(score) | ScoreBound::Exclusive(score) => Some(score_type(*score)),
+        ScoreBound::Unbounded => None,
+    })
+}
+
+fn is_sorted_vec(ty: &ItemType) -> bool {
+    matches!(
+        ty,
+        ItemType::SortedVec | ItemType::SortedVecI64 | ItemType::SortedVecU64 | ItemType::SortedVecF64
+    )
+}
+
+/// Evaluates `$body` with `$S` standing for the score type of the sorted vec
+/// at `$key`. Missing keys and keys of other types go through the type given
+/// by `$hint`, the scores of the request if it has any, or else `i32`. They
+/// then read as empty or fail with the type error respectively, rather than
+/// failing to convert the request's scores.
+macro_rules! with_score_type {
+    ($store:expr, $key:expr, $S:ident => $body:expr) => {
+        with_score_type!($store, $key, None, $S => $body)
+    };
+    ($store:expr, $key:expr, $hint:expr, $S:ident => $body:expr) => {
+        match $store.item_type($key)?.filter(is_sorted_vec).or($hint) {
+            Some(ItemType::SortedVecI64) => {
+                type $S = i64;
+                $body
+            }
+            Some(ItemType::SortedVecU64) => {
+                type $S = u64;
+                $body
+            }
+            Some(ItemType::SortedVecF64) => {
+                type $S = F64;
+                $body
+            }
+            _ => {
+                type $S = i32;
+                $body
+            }
+        }
+    };
+}
+
+fn sv_scored_insert(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecScoredInsertQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    let result: bool = match req.score {
+        ScoreValue::I32(score) => store.sv_insert(&key, &(score, req.value), req.overwrite)?,
+        ScoreValue::I64(score) => store.sv_insert(&key, &(score, req.value), req.overwrite)?,
+        ScoreValue::U64(score) => store.sv_insert(&key, &(score, req.value), req.overwrite)?,
+        ScoreValue::F64(score) => store.sv_insert(&key, &(F64(score), req.value), req.overwrite)?,
+    };
+    Ok(serialize(KeyVecInsertResponse { success: result })?)
+}
+
+fn sv_scored_get(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecGetQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    with_score_type!(store, &key, S => scored_values(store.sv_into_vec::<S>(&key)?))
+}
+
+fn sv_scored_tail_off(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecTailOffQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    let result: usize = with_score_type!(store, &key, S => store.sv_tail_off::<S>(&key, req.remain)?);
+    Ok(serialize(KeyVecTailOffResponse { len: result })?)
+}
+
+fn sv_scored_remove_item(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecScoredRemoveQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    let result: bool = match req.score {
+        ScoreValue::I32(score) => store.sv_remove_item(&key, (score, vec![]))?,
+        ScoreValue::I64(score) => store.sv_remove_item(&key, (score, vec![]))?,
+        ScoreValue::U64(score) => store.sv_remove_item(&key, (score, vec![]))?,
+        ScoreValue::F64(score) => store.sv_remove_item(&key, (F64(score), vec![]))?,
+    };
+    Ok(serialize(KeyVecRemoveItemResponse { success: result })?)
+}
+
+fn sv_range_by_score(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecScoreRangeQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    with_score_type!(store, &key, range_type(req.min, req.max), S => scored_values(store.sv_range_by_score::<S>(
+        &key,
+        bound(req.min)?,
+        bound(req.max)?,
+        req.offset as usize,
+        req.limit.map(|limit| limit as usize),
+        req.reverse,
+    )?))
+}
+
+fn sv_range_by_rank(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecRankRangeQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    with_score_type!(store, &key, S => scored_values(store.sv_range_by_rank::<S>(
+        &key,
+        req.offset as usize,
+        req.limit.map(|limit| limit as usize),
+        req.reverse,
+    )?))
+}
+
+fn sv_count(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecCountQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    let result: usize =
+        with_score_type!(store, &key, range_type(req.min, req.max), S => {
+            store.sv_count::<S>(&key, bound(req.min)?, bound(req.max)?)?
+        });
+    Ok(serialize(KeyVecCountResponse { count: result as u64 })?)
+}
+
+/// Takes the same query as `KeyVecTailOff`, keeping the highest scores instead
+fn sv_head_off(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecTailOffQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    let result: usize = with_score_type!(store, &key, S => store.sv_head_off::<S>(&key, req.remain)?);
+    Ok(serialize(KeyVecTailOffResponse { len: result })?)
+}
+
+fn sv_pop_min(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecPopQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    with_score_type!(store, &key, S => scored_values(store.sv_pop_min::<S>(&key, req.count as usize)?))
+}
+
+fn sv_pop_max(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecPopQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    with_score_type!(store, &key, S => scored_values(store.sv_pop_max::<S>(&key, req.count as usize)?))
+}
+
+fn sv_remove_range_by_score(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: KeyVecRemoveRangeQuery,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = ns.key(&req.key);
+    let result: usize = with_score_type!(store, &key, range_type(req.min, req.max), S => {
+        store.sv_remove_range_by_score::<S>(&key, bound(req.min)?, bound(req.max)?)?
+    });
+    Ok(serialize(KeyVecCountResponse { count: result as u64 })?)
+}
+
+fn set_is_member(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: SetMemberRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: bool = store.sismember(&ns.key(&req.key), &req.member)?;
+    Ok(serialize(SetMemberResponse { is_member: result })?)
+}
+
+fn set_are_members(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: SetMembersRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<bool> = store.smismember(&ns.key(&req.key), &req.members)?;
+    Ok(serialize(SetMembersResponse { are_members: result })?)
+}
+
+fn set_card(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: SetCardRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.scard(&ns.key(&req.key))?;
+    Ok(serialize(SetCardResponse { len: result as u64 })?)
+}
+
+fn set_diff(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: SetDiffRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<Vec<u8>> = store.sdiff(&ns.keys(req.keys))?;
+    Ok(serialize(SetQueryResponse { values: result })?)
+}
+
+fn set_random(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: SetRandomRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<Vec<u8>> = store.srandmember(&ns.key(&req.key), req.count as usize)?;
+    Ok(serialize(SetQueryResponse { values: result })?)
+}
+
+fn set_pop(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetRandomRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Vec<Vec<u8>> = store.spop(&ns.key(&req.key), req.count as usize)?;
+    Ok(serialize(SetQueryResponse { values: result })?)
+}
+
+fn set_move(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetMoveRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: bool = store.smove(&ns.key(&req.source), &ns.key(&req.destination), req.member)?;
+    Ok(serialize(SetMoveResponse { moved: result })?)
+}
+
+fn set_remove_members(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetMembersRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.srem_members(&ns.key(&req.key), &req.members)?;
+    Ok(serialize(SetCardResponse { len: result as u64 })?)
+}
+
+fn set_union_store(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetStoreRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.sunionstore(&ns.key(&req.destination), ns.keys(req.keys))?;
+    Ok(serialize(SetCardResponse { len: result as u64 })?)
+}
+
+fn set_inter_store(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetStoreRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.sinterstore(&ns.key(&req.destination), ns.keys(req.keys))?;
+    Ok(serialize(SetCardResponse { len: result as u64 })?)
+}
+
+fn set_diff_store(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: SetStoreRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.sdiffstore(&ns.key(&req.destination), &ns.keys(req.keys))?;
+    Ok(serialize(SetCardResponse { len: result as u64 })?)
+}
+
+fn zadd(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ZAddRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let members = req
+        .members
+        .into_iter()
+        .map(|m| (m.member, m.score))
+        .collect();
+    let result = store.zadd(&ns.key(&req.key), members)?;
+    Ok(serialize(ZCountResponse { count: result as u64 })?)
+}
+
+fn zincrby(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ZIncrByRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: i64 = store.zincrby(&ns.key(&req.key), req.member, req.increment)?;
+    Ok(serialize(ZScoreResponse {
+        score: result,
+        exists: true,
+    })?)
+}
+
+fn zrem(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: ZRemRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.zrem(&ns.key(&req.key), &req.members)?;
+    Ok(serialize(ZCountResponse { count: result as u64 })?)
+}
+
+fn zscore(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: ZMemberRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result: Option<i64> = store.zscore(&ns.key(&req.key), &req.member)?;
+    Ok(serialize(ZScoreResponse {
+        score: result.unwrap_or_default(),
+        exists: result.is_some(),
+    })?)
+}
+
+fn zrank(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: ZMemberRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.zrank(&ns.key(&req.key), &req.member)?;
+    rank_response(result)
+}
+
+fn zrevrank(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: ZMemberRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.zrevrank(&ns.key(&req.key), &req.member)?;
+    rank_response(result)
+}
+
+fn rank_response(rank: Option<usize>) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(serialize(ZRankResponse {
+        rank: rank.unwrap_or_default() as u64,
+        exists: rank.is_some(),
+    })?)
+}
+
+fn zcard(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: ZCardRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.zcard(&ns.key(&req.key))?;
+    Ok(serialize(ZCardResponse { len: result as u64 })?)
+}
+
+fn zrange(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: ZRangeRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.zrange(&ns.key(&req.key), req.start, req.stop)?;
+    Ok(serialize(ZRangeResponse {
+        members: result
+            .into_iter()
+            .map(|(member, score)| ScoredMember { member, score })
+            .collect(),
+    })?)
+}
+
+fn hset(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: HSetRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let fields = req.fields.into_iter().map(|f| (f.field, f.value)).collect();
+    let result = store.hset(&ns.key(&req.key), fields)?;
+    Ok(serialize(HCountResponse { count: result as u64 })?)
+}
+
+fn hsetnx(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: HSetNxRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.hsetnx(&ns.key(&req.key), req.field, req.value)?;
+    Ok(serialize(HSetNxResponse { success: result })?)
+}
+
+fn hdel(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: HFieldsRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.hdel(&ns.key(&req.key), &req.fields)?;
+    Ok(serialize(HCountResponse { count: result as u64 })?)
+}
+
+fn hincrby(
+    store: &mut KeyValueStore,
+    ns: &Namespace,
+    req: HIncrByRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.hincrby(&ns.key(&req.key), req.field, req.increment)?;
+    Ok(serialize(HIncrByResponse { value: result })?)
+}
+
+fn hget(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: HFieldRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.hget(&ns.key(&req.key), &req.field)?;
+    Ok(serialize(HGetResponse {
+        exists: result.is_some(),
+        value: result.unwrap_or_default(),
+    })?)
+}
+
+fn hmget(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: HFieldsRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.hmget(&ns.key(&req.key), &req.fields)?;
+    Ok(serialize(HMGetResponse { values: result })?)
+}
+
+fn hexists(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: HFieldRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.hexists(&ns.key(&req.key), &req.field)?;
+    Ok(serialize(HExistsResponse { exists: result })?)
+}
+
+fn hlen(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: HKeyRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.hlen(&ns.key(&req.key))?;
+    Ok(serialize(HLenResponse { len: result as u64 })?)
+}
+
+fn hkeys(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: HKeyRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.hkeys(&ns.key(&req.key))?;
+    Ok(serialize(HKeysResponse { fields: result })?)
+}
+
+fn hvals(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: HKeyRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.hvals(&ns.key(&req.key))?;
+    Ok(serialize(HValsResponse { values: result })?)
+}
+
+fn hgetall(
+    store: &KeyValueStore,
+    ns: &Namespace,
+    req: HKeyRequest,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let result = store.hgetall(&ns.key(&req.key))?;
+    Ok(serialize(HGetAllResponse {
+        fields: result
+            .into_iter()
+            .map(|(field, value)| HashField { field, value })
+            .collect(),
+    })?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_missing_sorted_vecs_read_empty_for_any_score_type() {
+        let mut store = KeyValueStore::new();
+        let ns = Namespace::actor("MTEST");
+        for &(min, max) in &[
+            (ScoreValue::I64(-1), ScoreValue::I64(i64::MAX)),
+            (ScoreValue::U64(0), ScoreValue::U64(u64::MAX)),
+            (ScoreValue::F64(-0.5), ScoreValue::F64(0.5)),
+        ] {
+            let (min, max) = (ScoreBound::Inclusive(min), ScoreBound::Exclusive(max));
+            let range = serialize(KeyVecScoreRangeQuery {
+                key: "missing".to_string(),
+                min,
+                max: ScoreBound::Unbounded,
+                offset: 0,
+                limit: None,
+                reverse: false,
+            })
+            .unwrap();
+            let resp = query(&store, &ns, protocol::OP_KEYVEC_RANGE_BY_SCORE, &range).unwrap();
+            let resp: ScoredValuesResponse = deserialize(&resp).unwrap();
+            assert!(resp.values.is_empty());
+
+            let count = serialize(KeyVecCountQuery {
+                key: "missing".to_string(),
+                min: ScoreBound::Unbounded,
+                max,
+            })
+            .unwrap();
+            let resp = query(&store, &ns, protocol::OP_KEYVEC_COUNT, &count).unwrap();
+            let resp: KeyVecCountResponse = deserialize(&resp).unwrap();
+            assert_eq!(0, resp.count);
+
+            let remove = serialize(KeyVecRemoveRangeQuery {
+                key: "missing".to_string(),
+                min,
+                max,
+            })
+            .unwrap();
+            let resp = apply(&mut store, &ns, protocol::OP_KEYVEC_REMOVE_RANGE_BY_SCORE, &remove).unwrap();
+            let resp: KeyVecCountResponse = deserialize(&resp).unwrap();
+            assert_eq!(0, resp.count);
+            assert!(!store.exists(&ns.key("missing")).unwrap());
+        }
+
+        // Keys of other types still report what they hold
+        store.sadd(&ns.key("set"), b"member".to_vec()).unwrap();
+        let count = serialize(KeyVecCountQuery {
+            key: "set".to_string(),
+            min: ScoreBound::Inclusive(ScoreValue::U64(0)),
+            max: ScoreBound::Unbounded,
+        })
+        .unwrap();
+        let e = KvError::from(query(&store, &ns, protocol::OP_KEYVEC_COUNT, &count).unwrap_err());
+        assert_eq!(
+            KvError::WrongType {
+                expected: ItemType::SortedVecU64,
+                actual: ItemType::Set,
+            },
+            e
+        );
+    }
+
+    #[test]
+    fn test_scan_by_type() {
+        let mut store = KeyValueStore::new();
+        let ns = Namespace::actor("MTEST");
+        store.sadd(&ns.key("roles"), b"admin".to_vec()).unwrap();
+        store.set(&ns.key("name"), b"bob".to_vec()).unwrap();
+        let scan = serialize(ScanRequest {
+            cursor: None,
+            pattern: None,
+            item_type: Some(ItemType::Set),
+            count: 0,
+        })
+        .unwrap();
+        let resp = query(&store, &ns, protocol::OP_SCAN, &scan).unwrap();
+        let resp: ScanResponse = deserialize(&resp).unwrap();
+        assert_eq!(vec!["roles".to_string()], resp.keys);
+        assert_eq!(None, resp.cursor);
+    }
+}
@@ -0,0 +1,778 @@
+//! Operations handled by this provider in addition to the ones defined in
+//! `tea_codec::keyvalue`. Messages follow the same conventions: every request
+//! names the key it works on and is msgpack encoded by the caller.
+
+use crate::kv::ItemType;
+use serde::{Deserialize, Serialize};
+
+pub const OP_EXPIRE: &str = "Expire";
+pub const OP_EXPIRE_AT: &str = "ExpireAt";
+pub const OP_PERSIST: &str = "Persist";
+pub const OP_TTL: &str = "Ttl";
+pub const OP_SET_EX: &str = "SetEx";
+pub const OP_SET_EX_AT: &str = "SetExAt";
+pub const OP_LPUSH: &str = "LPush";
+pub const OP_RPUSH: &str = "RPush";
+pub const OP_LPOP: &str = "LPop";
+pub const OP_RPOP: &str = "RPop";
+pub const OP_LINDEX: &str = "LIndex";
+pub const OP_LSET: &str = "LSet";
+pub const OP_LINSERT: &str = "LInsert";
+pub const OP_LTRIM: &str = "LTrim";
+pub const OP_LLEN: &str = "LLen";
+pub const OP_LMOVE: &str = "LMove";
+pub const OP_SET_IS_MEMBER: &str = "SIsMember";
+pub const OP_SET_ARE_MEMBERS: &str = "SMIsMember";
+pub const OP_SET_CARD: &str = "SCard";
+pub const OP_SET_DIFF: &str = "SDiff";
+pub const OP_SET_POP: &str = "SPop";
+pub const OP_SET_RANDOM: &str = "SRandMember";
+pub const OP_SET_MOVE: &str = "SMove";
+pub const OP_SET_REMOVE_MEMBERS: &str = "SRemMembers";
+pub const OP_SET_UNION_STORE: &str = "SUnionStore";
+pub const OP_SET_INTER_STORE: &str = "SInterStore";
+pub const OP_SET_DIFF_STORE: &str = "SDiffStore";
+pub const OP_ZADD: &str = "ZAdd";
+pub const OP_ZINCRBY: &str = "ZIncrBy";
+pub const OP_ZREM: &str = "ZRem";
+pub const OP_ZSCORE: &str = "ZScore";
+pub const OP_ZRANK: &str = "ZRank";
+pub const OP_ZREVRANK: &str = "ZRevRank";
+pub const OP_ZCARD: &str = "ZCard";
+pub const OP_ZRANGE: &str = "ZRange";
+pub const OP_KEYVEC_RANGE_BY_SCORE: &str = "KeyVecRangeByScore";
+pub const OP_KEYVEC_RANGE_BY_RANK: &str = "KeyVecRangeByRank";
+pub const OP_KEYVEC_COUNT: &str = "KeyVecCount";
+pub const OP_KEYVEC_POP_MIN: &str = "KeyVecPopMin";
+pub const OP_KEYVEC_POP_MAX: &str = "KeyVecPopMax";
+pub const OP_KEYVEC_HEADOFF: &str = "KeyVecHeadOff";
+pub const OP_KEYVEC_REMOVE_RANGE_BY_SCORE: &str = "KeyVecRemoveRangeByScore";
+pub const OP_KEYVEC_SCORED_INSERT: &str = "KeyVecScoredInsert";
+pub const OP_KEYVEC_SCORED_GET: &str = "KeyVecScoredGet";
+pub const OP_KEYVEC_SCORED_TAILOFF: &str = "KeyVecScoredTailOff";
+pub const OP_KEYVEC_SCORED_REMOVE_ITEM: &str = "KeyVecScoredRemoveItem";
+pub const OP_HSET: &str = "HSet";
+pub const OP_HSETNX: &str = "HSetNx";
+pub const OP_HGET: &str = "HGet";
+pub const OP_HMGET: &str = "HMGet";
+pub const OP_HDEL: &str = "HDel";
+pub const OP_HEXISTS: &str = "HExists";
+pub const OP_HLEN: &str = "HLen";
+pub const OP_HKEYS: &str = "HKeys";
+pub const OP_HVALS: &str = "HVals";
+pub const OP_HGETALL: &str = "HGetAll";
+pub const OP_HINCRBY: &str = "HIncrBy";
+pub const OP_INCR_BY: &str = "IncrBy";
+pub const OP_DECR_BY: &str = "DecrBy";
+pub const OP_INCR_BY_FLOAT: &str = "IncrByFloat";
+pub const OP_EXEC: &str = "Exec";
+pub const OP_VERSIONS: &str = "Versions";
+pub const OP_GET_VERSIONED: &str = "GetVersioned";
+pub const OP_SET_IF: &str = "SetIf";
+pub const OP_SET_IF_AT: &str = "SetIfAt";
+pub const OP_GETSET: &str = "GetSet";
+pub const OP_COMPARE_AND_SWAP: &str = "CompareAndSwap";
+pub const OP_MGET: &str = "MGet";
+pub const OP_MSET: &str = "MSet";
+pub const OP_MSETNX: &str = "MSetNx";
+pub const OP_MDEL: &str = "MDel";
+pub const OP_SCAN: &str = "Scan";
+
+// Error codes. A failed call reports `<code>: <detail>` to the actor.
+pub const ERR_NO_SUCH_KEY: &str = "NO_SUCH_KEY";
+pub const ERR_WRONG_TYPE: &str = "WRONG_TYPE";
+pub const ERR_OUT_OF_RANGE: &str = "OUT_OF_RANGE";
+pub const ERR_DECODE: &str = "DECODE_ERROR";
+pub const ERR_INVALID_REQUEST: &str = "INVALID_REQUEST";
+pub const ERR_UNKNOWN_OP: &str = "UNKNOWN_OP";
+pub const ERR_QUOTA_EXCEEDED: &str = "QUOTA_EXCEEDED";
+pub const ERR_CONFLICT: &str = "CONFLICT";
+pub const ERR_INTERNAL: &str = "INTERNAL";
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ExpireRequest {
+    pub key: String,
+    pub ttl_ms: u64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ExpireAtRequest {
+    pub key: String,
+    /// Milliseconds since the unix epoch
+    pub at_ms: u64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ExpireResponse {
+    pub success: bool,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct PersistRequest {
+    pub key: String,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct TtlRequest {
+    pub key: String,
+}
+
+/// `ttl_ms` is -2 if the key does not exist and -1 if it has no expiry
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct TtlResponse {
+    pub ttl_ms: i64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetExRequest {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub ttl_ms: u64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetExAtRequest {
+    pub key: String,
+    pub value: Vec<u8>,
+    /// Milliseconds since the unix epoch
+    pub at_ms: u64,
+}
+
+/// Used by both `LPush` and `RPush`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct PushRequest {
+    pub key: String,
+    pub values: Vec<Vec<u8>>,
+}
+
+/// Used by both `LPop` and `RPop`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct PopRequest {
+    pub key: String,
+    pub count: u64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListValuesResponse {
+    pub values: Vec<Vec<u8>>,
+}
+
+/// Indices count from the tail when negative, -1 being the last value
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListIndexRequest {
+    pub key: String,
+    pub index: i64,
+}
+
+/// `exists` is false if the key is missing or the index lies outside the list
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListValueResponse {
+    pub value: Vec<u8>,
+    pub exists: bool,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListSetRequest {
+    pub key: String,
+    pub index: i64,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListInsertRequest {
+    pub key: String,
+    pub pivot: Vec<u8>,
+    pub value: Vec<u8>,
+    /// Insert before the pivot rather than after it
+    pub before: bool,
+}
+
+/// `len` is 0 if the key is missing and -1 if the pivot is not in the list
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListInsertResponse {
+    pub len: i64,
+}
+
+/// `start` and `stop` are both inclusive and count from the tail when negative
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListTrimRequest {
+    pub key: String,
+    pub start: i64,
+    pub stop: i64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListLenRequest {
+    pub key: String,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListLenResponse {
+    pub len: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ListEnd {
+    /// The head of the list
+    Left,
+    /// The tail of the list
+    Right,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ListMoveRequest {
+    pub source: String,
+    pub destination: String,
+    pub from: ListEnd,
+    pub to: ListEnd,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetMemberRequest {
+    pub key: String,
+    pub member: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetMemberResponse {
+    pub is_member: bool,
+}
+
+/// Used by `SMIsMember` and `SRemMembers`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetMembersRequest {
+    pub key: String,
+    pub members: Vec<Vec<u8>>,
+}
+
+/// One flag per requested member, in the same order
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetMembersResponse {
+    pub are_members: Vec<bool>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetCardRequest {
+    pub key: String,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetCardResponse {
+    pub len: u64,
+}
+
+/// Members of the first set that are in none of the others
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetDiffRequest {
+    pub keys: Vec<String>,
+}
+
+/// Used by `SPop` and `SRandMember`. At most `count` distinct members are returned.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetRandomRequest {
+    pub key: String,
+    pub count: u64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetMoveRequest {
+    pub source: String,
+    pub destination: String,
+    pub member: Vec<u8>,
+}
+
+/// `moved` is false if the member was not in the source set
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetMoveResponse {
+    pub moved: bool,
+}
+
+/// Used by `SUnionStore`, `SInterStore` and `SDiffStore`. The result replaces
+/// whatever `destination` held; its cardinality comes back as a `SetCardResponse`.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetStoreRequest {
+    pub destination: String,
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ScoredMember {
+    pub member: Vec<u8>,
+    pub score: i64,
+}
+
+/// Members already in the sorted set get the new score
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZAddRequest {
+    pub key: String,
+    pub members: Vec<ScoredMember>,
+}
+
+/// Used by `ZAdd` and `ZRem`: how many members were added or removed
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZCountResponse {
+    pub count: u64,
+}
+
+/// A new member starts from a score of 0
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZIncrByRequest {
+    pub key: String,
+    pub member: Vec<u8>,
+    pub increment: i64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZRemRequest {
+    pub key: String,
+    pub members: Vec<Vec<u8>>,
+}
+
+/// Used by `ZScore`, `ZRank` and `ZRevRank`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZMemberRequest {
+    pub key: String,
+    pub member: Vec<u8>,
+}
+
+/// `exists` is false if the key is missing or the member is not in the sorted set
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZScoreResponse {
+    pub score: i64,
+    pub exists: bool,
+}
+
+/// `exists` is false if the key is missing or the member is not in the sorted set
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZRankResponse {
+    pub rank: u64,
+    pub exists: bool,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZCardRequest {
+    pub key: String,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZCardResponse {
+    pub len: u64,
+}
+
+/// Ranks from `start` to `stop`, both inclusive and counted from the highest
+/// score when negative
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZRangeRequest {
+    pub key: String,
+    pub start: i64,
+    pub stop: i64,
+}
+
+/// Lowest score first
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZRangeResponse {
+    pub members: Vec<ScoredMember>,
+}
+
+/// A sorted vec score. A key gets the score type of the first value inserted
+/// under it and keeps it; scores of any other type are rejected with
+/// `WRONG_TYPE`. `I32` keys are the ones the original `KeyVec*` operations
+/// work on.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ScoreValue {
+    I32(i32),
+    I64(i64),
+    U64(u64),
+    /// Ordered by `f64::total_cmp`
+    F64(f64),
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ScoredValue {
+    pub score: ScoreValue,
+    pub value: Vec<u8>,
+}
+
+/// Entries of a sorted vec of any score type
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ScoredValuesResponse {
+    pub values: Vec<ScoredValue>,
+}
+
+/// One end of a range of sorted vec scores
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ScoreBound {
+    Inclusive(ScoreValue),
+    Exclusive(ScoreValue),
+    Unbounded,
+}
+
+/// Like `KeyVecInsertQuery`, for any score type. Answered with a `KeyVecInsertResponse`.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyVecScoredInsertQuery {
+    pub key: String,
+    pub score: ScoreValue,
+    pub value: Vec<u8>,
+    pub overwrite: bool,
+}
+
+/// Like `KeyVecRemoveItemQuery`, for any score type. Answered with a
+/// `KeyVecRemoveItemResponse`.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyVecScoredRemoveQuery {
+    pub key: String,
+    pub score: ScoreValue,
+}
+
+/// Entries with scores from `min` to `max`, lowest first or highest first if
+/// `reverse`. `offset` entries are skipped in that order and at most `limit`
+/// returned; no `limit` returns all the rest. The entries come back as a
+/// `ScoredValuesResponse`.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyVecScoreRangeQuery {
+    pub key: String,
+    pub min: ScoreBound,
+    pub max: ScoreBound,
+    pub offset: u64,
+    pub limit: Option<u64>,
+    pub reverse: bool,
+}
+
+/// At most `limit` entries starting at rank `offset`, counted from the lowest
+/// score or from the highest if `reverse`. The entries come back as a
+/// `ScoredValuesResponse`.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyVecRankRangeQuery {
+    pub key: String,
+    pub offset: u64,
+    pub limit: Option<u64>,
+    pub reverse: bool,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyVecCountQuery {
+    pub key: String,
+    pub min: ScoreBound,
+    pub max: ScoreBound,
+}
+
+/// Also the response of `KeyVecRemoveRangeByScore`, counting the entries removed
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyVecCountResponse {
+    pub count: u64,
+}
+
+/// Used by `KeyVecPopMin` and `KeyVecPopMax`. The removed entries come back as
+/// a `ScoredValuesResponse`, starting from the end they were popped from.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyVecPopQuery {
+    pub key: String,
+    pub count: u64,
+}
+
+/// Removes the entries with scores from `min` to `max`; how many comes back
+/// as a `KeyVecCountResponse`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyVecRemoveRangeQuery {
+    pub key: String,
+    pub min: ScoreBound,
+    pub max: ScoreBound,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HashField {
+    pub field: String,
+    pub value: Vec<u8>,
+}
+
+/// Fields the hash does not have are added, the others get the new value.
+/// Fields not named in the request are left alone.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HSetRequest {
+    pub key: String,
+    pub fields: Vec<HashField>,
+}
+
+/// Used by `HSet` and `HDel`: how many fields were added or removed
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HCountResponse {
+    pub count: u64,
+}
+
+/// Only sets the field if the hash does not have it yet
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HSetNxRequest {
+    pub key: String,
+    pub field: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HSetNxResponse {
+    pub success: bool,
+}
+
+/// Used by `HGet` and `HExists`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HFieldRequest {
+    pub key: String,
+    pub field: String,
+}
+
+/// `exists` is false if the key is missing or the hash does not have the field
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HGetResponse {
+    pub value: Vec<u8>,
+    pub exists: bool,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HExistsResponse {
+    pub exists: bool,
+}
+
+/// Used by `HMGet` and `HDel`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HFieldsRequest {
+    pub key: String,
+    pub fields: Vec<String>,
+}
+
+/// One value per requested field, in the same order; `None` for missing fields
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HMGetResponse {
+    pub values: Vec<Option<Vec<u8>>>,
+}
+
+/// Used by `HLen`, `HKeys`, `HVals` and `HGetAll`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HKeyRequest {
+    pub key: String,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HLenResponse {
+    pub len: u64,
+}
+
+/// Field names in byte order
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HKeysResponse {
+    pub fields: Vec<String>,
+}
+
+/// Values in the byte order of their field names
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HValsResponse {
+    pub values: Vec<Vec<u8>>,
+}
+
+/// Fields in byte order
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HGetAllResponse {
+    pub fields: Vec<HashField>,
+}
+
+/// The field holds a decimal integer and starts from 0 if the hash does not have it
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HIncrByRequest {
+    pub key: String,
+    pub field: String,
+    pub increment: i64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct HIncrByResponse {
+    pub value: i64,
+}
+
+/// Used by `IncrBy` and `DecrBy`. A missing counter starts from 0.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct CounterRequest {
+    pub key: String,
+    pub delta: i64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct CounterResponse {
+    pub value: i64,
+}
+
+/// Float counters are a type of their own; `IncrBy` and `Add` don't work on them
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct FloatCounterRequest {
+    pub key: String,
+    pub delta: f64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct FloatCounterResponse {
+    pub value: f64,
+}
+
+/// One command of a transaction: any operation other than `Exec` itself, with
+/// its request encoded just as it would be for a call of its own
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Command {
+    pub op: String,
+    pub msg: Vec<u8>,
+}
+
+/// Runs the commands in order under a single lock. If one of them fails, the
+/// changes made by the others are rolled back and its error is returned. If
+/// any of the `watch`ed keys no longer has the version given, nothing is run
+/// and the call fails with `CONFLICT`.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ExecRequest {
+    #[serde(default)]
+    pub watch: Vec<WatchedKey>,
+    pub commands: Vec<Command>,
+}
+
+/// A key and the version it had when the caller read it, 0 if it was missing
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct WatchedKey {
+    pub key: String,
+    pub version: u64,
+}
+
+/// The encoded response of each command, in the order of the commands
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ExecResponse {
+    pub results: Vec<Vec<u8>>,
+}
+
+/// Versions only grow, and a missing key has version 0
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct VersionsRequest {
+    pub keys: Vec<String>,
+}
+
+/// One version per requested key, in the same order
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct VersionsResponse {
+    pub versions: Vec<u64>,
+}
+
+/// The response of `GetVersioned`, which takes a `GetRequest`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct VersionedGetResponse {
+    pub value: Vec<u8>,
+    pub exists: bool,
+    pub version: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum SetCondition {
+    Always,
+    /// Only if the key does not exist, like `SET NX`
+    IfAbsent,
+    /// Only if the key exists, whatever it holds, like `SET XX`
+    IfPresent,
+}
+
+/// Writes the value like `Set` if the condition holds, giving it a time to
+/// live if `ttl_ms` is set and clearing any expiry otherwise
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetIfRequest {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub condition: SetCondition,
+    pub ttl_ms: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetIfAtRequest {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub condition: SetCondition,
+    /// Milliseconds since the unix epoch
+    pub at_ms: Option<u64>,
+}
+
+/// Also the response of `CompareAndSwap`. `success` is true if the value was written.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetIfResponse {
+    pub success: bool,
+}
+
+/// Writes the value like `Set`. The response is a `GetResponse` with the scalar it replaced.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct GetSetRequest {
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+/// Replaces the scalar with `value` only if it equals `expected` byte for
+/// byte, keeping its expiry
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct CompareAndSwapRequest {
+    pub key: String,
+    pub expected: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Used by `MGet` and `MDel`
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct MKeysRequest {
+    pub keys: Vec<String>,
+}
+
+/// Like a `GetResponse` for each of the keys
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct MGetValue {
+    pub value: Vec<u8>,
+    pub exists: bool,
+}
+
+/// One value per requested key, in the same order
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct MGetResponse {
+    pub values: Vec<MGetValue>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyValuePair {
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+/// Used by `MSet` and `MSetNx`. Each value is written like `Set`.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct MSetRequest {
+    pub entries: Vec<KeyValuePair>,
+}
+
+/// Used by `MSet` and `MDel`: whether each key existed before, in request order
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct MExistedResponse {
+    pub existed: Vec<bool>,
+}
+
+/// `MSetNx` writes all the values if none of the keys existed, and none otherwise
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct MSetNxResponse {
+    pub success: bool,
+    pub existed: Vec<bool>,
+}
+
+/// One page of a scan over the caller's keys, in byte order. Start without a
+/// `cursor` and pass back the one each response returns until it comes back
+/// as `None`. `count` is how many keys a page looks at: 100 if it is 0 and at
+/// most 10000. Keys that don't match the filters are skipped, so a page can
+/// hold fewer keys than that, or none, while the scan goes on.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ScanRequest {
+    pub cursor: Option<String>,
+    /// A glob pattern such as `user:*:session`
+    pub pattern: Option<String>,
+    /// Only keys holding this type
+    pub item_type: Option<ItemType>,
+    pub count: u64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ScanResponse {
+    pub keys: Vec<String>,
+    pub cursor: Option<String>,
+}
@@ -0,0 +1,403 @@
+//! Point-in-time copies of the whole `KeyValueStore` on disk.
+//!
+//! A snapshot file starts with a 4 byte magic and a little endian format
+//! version, followed by the msgpack encoded entries. Files are always written
+//! to a temporary sibling first and renamed over the previous snapshot, so a
+//! crash while saving leaves the last good snapshot in place.
+
+use crate::frame::{check_header, header, replace, temp_path, HEADER_LEN};
+use crate::kv::{ItemType, KeyValueItem, KeyValueStore};
+use crate::sorted_vec::F64;
+use crate::zset::SortedSet;
+use serde::{Deserialize, Serialize};
+use wascc_codec::{deserialize, serialize};
+
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// File the snapshot is written to. Snapshots are disabled unless this is set.
+pub const SNAPSHOT_PATH_KEY: &str = "snapshot_path";
+/// Minimum number of seconds between two snapshots
+pub const SNAPSHOT_INTERVAL_KEY: &str = "snapshot_interval_secs";
+/// Minimum number of writes since the last snapshot before a new one is taken
+pub const SNAPSHOT_MIN_WRITES_KEY: &str = "snapshot_min_writes";
+
+const MAGIC: &[u8; 4] = b"TKVS";
+const VERSION: u32 = 2;
+/// Version 1 snapshots predate the operation log and carry no sequence number
+const MIN_VERSION: u32 = 1;
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_MIN_WRITES: u64 = 1;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotConfig {
+    pub path: PathBuf,
+    pub interval: Duration,
+    pub min_writes: u64,
+}
+
+impl SnapshotConfig {
+    /// Reads the snapshot settings out of an actor binding. Returns `None` if
+    /// the binding does not ask for snapshots.
+    pub fn from_values(values: &HashMap<String, String>) -> Result<Option<Self>, Box<dyn Error>> {
+        let path = match values.get(SNAPSHOT_PATH_KEY) {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(None),
+        };
+        let interval = match values.get(SNAPSHOT_INTERVAL_KEY) {
+            Some(v) => v.parse()?,
+            None => DEFAULT_INTERVAL_SECS,
+        };
+        let min_writes = match values.get(SNAPSHOT_MIN_WRITES_KEY) {
+            Some(v) => v.parse()?,
+            None => DEFAULT_MIN_WRITES,
+        };
+        Ok(Some(SnapshotConfig {
+            path,
+            interval: Duration::from_secs(interval),
+            min_writes,
+        }))
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub enum SnapshotItem {
+    /// Counters were `i32`s before; msgpack reads those back as `i64`s just the same
+    Atomic(i64),
+    Scalar(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    SortedVec(Vec<(i32, Vec<u8>)>),
+    SortedSet(Vec<(Vec<u8>, i64)>),
+    SortedVecI64(Vec<(i64, Vec<u8>)>),
+    SortedVecU64(Vec<(u64, Vec<u8>)>),
+    SortedVecF64(Vec<(f64, Vec<u8>)>),
+    Hash(Vec<(String, Vec<u8>)>),
+    Float(f64),
+}
+
+impl SnapshotItem {
+    pub fn item_type(&self) -> ItemType {
+        match self {
+            SnapshotItem::Atomic(_) => ItemType::Atomic,
+            SnapshotItem::Float(_) => ItemType::Float,
+            SnapshotItem::Scalar(_) => ItemType::Scalar,
+            SnapshotItem::List(_) => ItemType::List,
+            SnapshotItem::Set(_) => ItemType::Set,
+            SnapshotItem::SortedVec(_) => ItemType::SortedVec,
+            SnapshotItem::SortedVecI64(_) => ItemType::SortedVecI64,
+            SnapshotItem::SortedVecU64(_) => ItemType::SortedVecU64,
+            SnapshotItem::SortedVecF64(_) => ItemType::SortedVecF64,
+            SnapshotItem::SortedSet(_) => ItemType::SortedSet,
+            SnapshotItem::Hash(_) => ItemType::Hash,
+        }
+    }
+}
+
+impl From<&KeyValueItem> for SnapshotItem {
+    fn from(item: &KeyValueItem) -> Self {
+        match item {
+            KeyValueItem::Atomic(x) => SnapshotItem::Atomic(*x),
+            KeyValueItem::Float(x) => SnapshotItem::Float(*x),
+            KeyValueItem::Scalar(s) => SnapshotItem::Scalar(s.clone()),
+            KeyValueItem::List(l) => SnapshotItem::List(l.iter().cloned().collect()),
+            KeyValueItem::Set(s) => SnapshotItem::Set(s.iter().cloned().collect()),
+            KeyValueItem::SortedVec(kvec) => SnapshotItem::SortedVec(kvec.as_slice().to_vec()),
+            KeyValueItem::SortedVecI64(kvec) => SnapshotItem::SortedVecI64(kvec.as_slice().to_vec()),
+            KeyValueItem::SortedVecU64(kvec) => SnapshotItem::SortedVecU64(kvec.as_slice().to_vec()),
+            KeyValueItem::SortedVecF64(kvec) => {
+                SnapshotItem::SortedVecF64(kvec.as_slice().iter().map(|(s, v)| (s.0, v.clone())).collect())
+            }
+            KeyValueItem::SortedSet(z) => {
+                SnapshotItem::SortedSet(z.iter().map(|(member, score)| (member.clone(), score)).collect())
+            }
+            KeyValueItem::Hash(h) => SnapshotItem::Hash(h.iter().map(|(f, v)| (f.clone(), v.clone())).collect()),
+        }
+    }
+}
+
+impl From<SnapshotItem> for KeyValueItem {
+    fn from(item: SnapshotItem) -> Self {
+        match item {
+            SnapshotItem::Atomic(x) => KeyValueItem::Atomic(x),
+            SnapshotItem::Float(x) => KeyValueItem::Float(x),
+            SnapshotItem::Scalar(s) => KeyValueItem::Scalar(s),
+            SnapshotItem::List(l) => KeyValueItem::List(l.into()),
+            SnapshotItem::Set(s) => KeyValueItem::Set(s.into_iter().collect::<BTreeSet<_>>()),
+            SnapshotItem::SortedVec(v) => KeyValueItem::SortedVec(v.into_iter().collect()),
+            SnapshotItem::SortedVecI64(v) => KeyValueItem::SortedVecI64(v.into_iter().collect()),
+            SnapshotItem::SortedVecU64(v) => KeyValueItem::SortedVecU64(v.into_iter().collect()),
+            SnapshotItem::SortedVecF64(v) => {
+                KeyValueItem::SortedVecF64(v.into_iter().map(|(s, v)| (F64(s), v)).collect())
+            }
+            SnapshotItem::SortedSet(v) => {
+                let mut z = SortedSet::new();
+                for (member, score) in v {
+                    z.insert(member, score);
+                }
+                KeyValueItem::SortedSet(z)
+            }
+            SnapshotItem::Hash(h) => KeyValueItem::Hash(h.into_iter().collect()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct SnapshotEntry {
+    key: String,
+    item: SnapshotItem,
+    expires_at: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Snapshot {
+    entries: Vec<SnapshotEntry>,
+    /// Operation log sequence number the snapshot was taken at
+    #[serde(default)]
+    seq: u64,
+}
+
+impl Snapshot {
+    pub fn capture(store: &KeyValueStore) -> Result<Self, Box<dyn Error>> {
+        let mut entries = Vec::new();
+        store.for_each_entry(|key, item, expires_at| {
+            entries.push(SnapshotEntry {
+                key: key.to_string(),
+                item: item.into(),
+                expires_at,
+            })
+        })?;
+        Ok(Snapshot {
+            entries,
+            seq: store.seq(),
+        })
+    }
+
+    /// Replaces everything in `store` with the snapshot's keys
+    pub fn restore(self, store: &mut KeyValueStore) -> Result<(), Box<dyn Error>> {
+        store.clear()?;
+        for entry in self.entries {
+            store.restore(&entry.key, entry.item.into(), entry.expires_at)?;
+        }
+        store.set_seq(self.seq);
+        Ok(())
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buf = header(MAGIC, VERSION);
+        buf.extend_from_slice(&serialize(self)?);
+        Ok(buf)
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, Box<dyn Error>> {
+        check_header(buf, MAGIC, MIN_VERSION, VERSION, "snapshot file")?;
+        deserialize(&buf[HEADER_LEN as usize..])
+    }
+
+    /// Atomically replaces the snapshot at `path`
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let tmp = temp_path(path);
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&self.encode()?)?;
+            file.sync_all()?;
+        }
+        replace(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Returns `None` if there is no snapshot at `path` yet
+    pub fn load(path: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        match fs::read(path) {
+            Ok(buf) => Ok(Some(Snapshot::decode(&buf)?)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Saves a snapshot whenever both the interval and the write count thresholds
+/// are met. The store is only read locked while it is being copied, never
+/// while the file is written. The thread exits once the store is dropped.
+pub fn spawn_snapshotter(store: Weak<RwLock<KeyValueStore>>, config: SnapshotConfig) {
+    thread::spawn(move || {
+        let mut last_save = Instant::now();
+        let mut saved_writes = 0;
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let store = match store.upgrade() {
+                Some(store) => store,
+                None => break,
+            };
+            if last_save.elapsed() < config.interval {
+                continue;
+            }
+            let (snapshot, writes) = {
+                let store = store.read().unwrap();
+                if store.write_count() - saved_writes < config.min_writes {
+                    continue;
+                }
+                (Snapshot::capture(&store), store.write_count())
+            };
+            let snapshot = match snapshot {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    error!("Capturing snapshot failed: {}", e);
+                    continue;
+                }
+            };
+            drop(store);
+            match snapshot.save(&config.path) {
+                Ok(()) => {
+                    trace!("Saved {} keys to {:?}", snapshot.len(), config.path);
+                    saved_writes = writes;
+                    last_save = Instant::now();
+                }
+                Err(e) => error!("Saving snapshot to {:?} failed: {}", config.path, e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Snapshot, SnapshotConfig, SNAPSHOT_PATH_KEY};
+    use crate::kv::{now_millis, KeyValueStore};
+    use crate::sorted_vec::F64;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("tea-kvp-{}-{}.snap", name, std::process::id()));
+        path
+    }
+
+    fn gen_store() -> KeyValueStore {
+        let mut store = KeyValueStore::new();
+        store.incr("counter", 5).unwrap();
+        store.set("scalar", "value".to_owned().into_bytes()).unwrap();
+        store.rpush("list", vec!["first".to_owned().into_bytes()]).unwrap();
+        store.rpush("list", vec!["second".to_owned().into_bytes()]).unwrap();
+        store.sadd("set", "bob".to_owned().into_bytes()).unwrap();
+        store.sadd("set", "alice".to_owned().into_bytes()).unwrap();
+        store.sv_insert("sorted", &(3, "three".to_owned().into_bytes()), false).unwrap();
+        store.sv_insert("sorted", &(1, "one".to_owned().into_bytes()), false).unwrap();
+        store.expire("scalar", 60_000).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = temp_path("round-trip");
+        let mut store = gen_store();
+        store.zadd("ranked", vec![(b"bob".to_vec(), 7), (b"alice".to_vec(), 7)]).unwrap();
+        store.sv_insert("timeline", &(1_600_000_000_000i64, b"then".to_vec()), false).unwrap();
+        store.sv_insert("prices", &(F64(2.5), b"then".to_vec()), false).unwrap();
+        store.hset("record", vec![("name".to_string(), b"tea".to_vec())]).unwrap();
+        Snapshot::capture(&store).unwrap().save(&path).unwrap();
+
+        let mut restored = KeyValueStore::new();
+        let snapshot = Snapshot::load(&path).unwrap().unwrap();
+        assert_eq!(9, snapshot.len());
+        snapshot.restore(&mut restored).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(6, restored.incr("counter", 1).unwrap());
+        assert_eq!("value".to_owned().into_bytes(), restored.get("scalar").unwrap());
+        assert!(restored.ttl("scalar").unwrap() > 0);
+        assert_eq!(store.lrange("list", 0, 10).unwrap(), restored.lrange("list", 0, 10).unwrap());
+        assert_eq!(2, restored.smembers("set".to_string()).unwrap().len());
+        assert_eq!(
+            store.sv_into_vec::<i32>("sorted").unwrap(),
+            restored.sv_into_vec::<i32>("sorted").unwrap()
+        );
+        assert_eq!(store.zrange("ranked", 0, -1).unwrap(), restored.zrange("ranked", 0, -1).unwrap());
+        assert_eq!(
+            store.sv_into_vec::<i64>("timeline").unwrap(),
+            restored.sv_into_vec::<i64>("timeline").unwrap()
+        );
+        assert_eq!(
+            store.sv_into_vec::<F64>("prices").unwrap(),
+            restored.sv_into_vec::<F64>("prices").unwrap()
+        );
+        assert_eq!(store.hgetall("record").unwrap(), restored.hgetall("record").unwrap());
+    }
+
+    #[test]
+    fn test_restore_replaces_existing_keys() {
+        let snapshot = Snapshot::capture(&gen_store()).unwrap();
+        let mut store = KeyValueStore::new();
+        store.set("stray", b"written before the first binding".to_vec()).unwrap();
+        store.incr("counter", 100).unwrap();
+        snapshot.restore(&mut store).unwrap();
+
+        assert!(!store.exists("stray").unwrap());
+        assert_eq!(6, store.incr("counter", 1).unwrap());
+    }
+
+    #[test]
+    fn test_expired_keys_are_not_saved() {
+        let mut store = gen_store();
+        store.expire_at("list", now_millis() - 1).unwrap();
+        assert_eq!(4, Snapshot::capture(&store).unwrap().len());
+    }
+
+    #[test]
+    fn test_missing_and_corrupt_files() {
+        let path = temp_path("corrupt");
+        assert!(Snapshot::load(&path).unwrap().is_none());
+
+        fs::write(&path, b"garbage").unwrap();
+        assert!(Snapshot::load(&path).is_err());
+
+        let mut buf = b"TKVS".to_vec();
+        buf.extend_from_slice(&99u32.to_le_bytes());
+        fs::write(&path, &buf).unwrap();
+        assert!(Snapshot::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_replaces_previous_snapshot() {
+        let path = temp_path("replace");
+        let mut store = gen_store();
+        Snapshot::capture(&store).unwrap().save(&path).unwrap();
+        store.del("counter").unwrap();
+        Snapshot::capture(&store).unwrap().save(&path).unwrap();
+
+        assert!(!crate::frame::temp_path(&path).exists());
+        assert_eq!(4, Snapshot::load(&path).unwrap().unwrap().len());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_config() {
+        let mut values = HashMap::new();
+        assert!(SnapshotConfig::from_values(&values).unwrap().is_none());
+
+        values.insert(SNAPSHOT_PATH_KEY.to_string(), "/tmp/kv.snap".to_string());
+        let config = SnapshotConfig::from_values(&values).unwrap().unwrap();
+        assert_eq!(PathBuf::from("/tmp/kv.snap"), config.path);
+        assert_eq!(1, config.min_writes);
+
+        values.insert(super::SNAPSHOT_INTERVAL_KEY.to_string(), "soon".to_string());
+        assert!(SnapshotConfig::from_values(&values).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+//! Sorted vectors: values kept in the order of their score, with at most one
+//! value per score. Entries live in a single `Vec` sorted by score, so finding
+//! a score is a binary search and the entries at a range of ranks or scores
+//! can be read as a slice without touching the rest.
+
+use std::cmp::Ordering;
+use std::iter::FromIterator;
+use std::ops::{Bound, Range};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SortedVec<S> {
+    entries: Vec<(S, Vec<u8>)>,
+}
+
+impl<S> Default for SortedVec<S> {
+    fn default() -> Self {
+        SortedVec { entries: Vec::new() }
+    }
+}
+
+impl<S: Copy + Ord> SortedVec<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn find(&self, score: S) -> Result<usize, usize> {
+        self.entries.binary_search_by(|(s, _)| s.cmp(&score))
+    }
+
+    /// Stores `value` under `score` and returns the value it replaced, if any
+    pub fn insert(&mut self, score: S, value: Vec<u8>) -> Option<Vec<u8>> {
+        match self.find(score) {
+            Ok(i) => Some(std::mem::replace(&mut self.entries[i].1, value)),
+            Err(i) => {
+                self.entries.insert(i, (score, value));
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, score: S) -> Option<&Vec<u8>> {
+        self.find(score).ok().map(|i| &self.entries[i].1)
+    }
+
+    pub fn remove(&mut self, score: S) -> Option<Vec<u8>> {
+        self.find(score).ok().map(|i| self.entries.remove(i).1)
+    }
+
+    /// Removes and returns the entries at the given positions, lowest score first
+    pub fn drain(&mut self, range: Range<usize>) -> Vec<(S, Vec<u8>)> {
+        self.entries.drain(range).collect()
+    }
+
+    /// All entries, lowest score first
+    pub fn as_slice(&self) -> &[(S, Vec<u8>)] {
+        &self.entries
+    }
+
+    /// The positions of the entries whose scores lie within both bounds
+    pub fn score_range(&self, min: Bound<S>, max: Bound<S>) -> Range<usize> {
+        let start = match min {
+            Bound::Included(min) => self.entries.partition_point(|(s, _)| *s < min),
+            Bound::Excluded(min) => self.entries.partition_point(|(s, _)| *s <= min),
+            Bound::Unbounded => 0,
+        };
+        let end = match max {
+            Bound::Included(max) => self.entries.partition_point(|(s, _)| *s <= max),
+            Bound::Excluded(max) => self.entries.partition_point(|(s, _)| *s < max),
+            Bound::Unbounded => self.entries.len(),
+        };
+        start..end.max(start)
+    }
+}
+
+/// Later entries replace earlier ones with the same score
+impl<S: Copy + Ord> FromIterator<(S, Vec<u8>)> for SortedVec<S> {
+    fn from_iter<I: IntoIterator<Item = (S, Vec<u8>)>>(iter: I) -> Self {
+        let mut kvec = SortedVec::new();
+        for (score, value) in iter {
+            kvec.insert(score, value);
+        }
+        kvec
+    }
+}
+
+/// An `f64` score. Scores are ordered by `f64::total_cmp`, which gives every
+/// value a place, NaN included, and tells -0.0 and 0.0 apart.
+#[derive(Clone, Copy, Debug)]
+pub struct F64(pub f64);
+
+impl PartialEq for F64 {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for F64 {}
+
+impl PartialOrd for F64 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for F64 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SortedVec, F64};
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    #[test]
+    fn test_score_range() {
+        let mut v = SortedVec::new();
+        for score in &[40, 10, 30, 20] {
+            assert_eq!(None, v.insert(*score, score.to_string().into_bytes()));
+        }
+        assert_eq!(Some(b"20".to_vec()), v.insert(20, b"twenty".to_vec()));
+        assert_eq!(vec![10, 20, 30, 40], v.as_slice().iter().map(|(s, _)| *s).collect::<Vec<_>>());
+
+        assert_eq!(1..3, v.score_range(Included(20), Included(30)));
+        assert_eq!(2..3, v.score_range(Excluded(20), Excluded(40)));
+        assert_eq!(0..4, v.score_range(Unbounded, Unbounded));
+        assert_eq!(0..1, v.score_range(Unbounded, Included(15)));
+        assert_eq!(4..4, v.score_range(Excluded(40), Unbounded));
+        assert!(v.score_range(Included(30), Included(20)).is_empty());
+        assert!(v.score_range(Included(25), Excluded(25)).is_empty());
+    }
+
+    #[test]
+    fn test_f64_order() {
+        let mut v = SortedVec::new();
+        for score in &[2.5, f64::NAN, -1.0, f64::INFINITY, 0.0, -0.0] {
+            v.insert(F64(*score), vec![]);
+        }
+        let scores: Vec<f64> = v.as_slice().iter().map(|(s, _)| s.0).collect();
+        assert_eq!(6, scores.len());
+        assert_eq!(&[-1.0, -0.0, 0.0, 2.5, f64::INFINITY], &scores[..5]);
+        assert!(scores[5].is_nan());
+        assert!(v.get(F64(f64::NAN)).is_some());
+        assert_eq!(1..3, v.score_range(Excluded(F64(-1.0)), Included(F64(0.0))));
+    }
+}
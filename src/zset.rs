@@ -0,0 +1,106 @@
+//! Sorted sets: unique members, each with a score that any number of members
+//! may share. Members are ordered by score, and members with the same score by
+//! their bytes, so the order is the same however the set was built.
+
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, i64>,
+    order: BTreeSet<(i64, Vec<u8>)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Gives `member` the score, adding it if it is not in the set yet.
+    /// Returns true if the member was added.
+    pub fn insert(&mut self, member: Vec<u8>, score: i64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(old) if old == score => false,
+            Some(old) => {
+                let mut entry = (old, member);
+                self.order.remove(&entry);
+                entry.0 = score;
+                self.order.insert(entry);
+                false
+            }
+            None => {
+                self.order.insert((score, member));
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.order.remove(&(score, member.to_vec()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<i64> {
+        self.scores.get(member).cloned()
+    }
+
+    /// The position of `member` counted from the lowest score. Takes time in
+    /// proportion to the rank.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.order.range(..(score, member.to_vec())).count())
+    }
+
+    /// Members and their scores from the lowest score to the highest
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Vec<u8>, i64)> + ExactSizeIterator {
+        self.order.iter().map(|(score, member)| (member, *score))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SortedSet;
+
+    fn members(set: &SortedSet) -> Vec<(String, i64)> {
+        set.iter()
+            .map(|(m, s)| (String::from_utf8(m.clone()).unwrap(), s))
+            .collect()
+    }
+
+    #[test]
+    fn test_scores_and_ranks() {
+        let mut set = SortedSet::new();
+        assert!(set.insert(b"carol".to_vec(), 30));
+        assert!(set.insert(b"bob".to_vec(), 10));
+        assert!(set.insert(b"alice".to_vec(), 10));
+        assert!(!set.insert(b"carol".to_vec(), 5));
+        assert!(!set.insert(b"carol".to_vec(), 5));
+
+        assert_eq!(3, set.len());
+        assert_eq!(
+            vec![("carol".to_string(), 5), ("alice".to_string(), 10), ("bob".to_string(), 10)],
+            members(&set)
+        );
+        assert_eq!(Some(5), set.score(b"carol"));
+        assert_eq!(Some(0), set.rank(b"carol"));
+        assert_eq!(Some(2), set.rank(b"bob"));
+        assert_eq!(None, set.rank(b"dave"));
+
+        assert!(set.remove(b"alice"));
+        assert!(!set.remove(b"alice"));
+        assert_eq!(Some(1), set.rank(b"bob"));
+        assert_eq!(None, set.score(b"alice"));
+    }
+}